@@ -0,0 +1,490 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Animated GIF, APNG and WebP. Frames are composited onto the full canvas as they are decoded.
+//
+// APNG frames are decoded by wrapping each one's data in a standalone PNG.
+
+use crate::{E, R};
+use ::image::{AnimationDecoder, DynamicImage, GenericImage, RgbaImage};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+/// Frames kept per animation, the rest are dropped.
+pub static MAX_FRAMES: usize = 64;
+
+// Browsers play frames with tiny delays at this speed, and so do we.
+static MIN_DELAY_MS: u32 = 20;
+static DEFAULT_DELAY_MS: u32 = 100;
+
+static PNG_MAGIC: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+pub struct Frame {
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+fn delay_ms(ms: u32) -> u32 {
+    if ms < MIN_DELAY_MS {
+        DEFAULT_DELAY_MS
+    } else {
+        ms
+    }
+}
+
+/// Whether the image at `path` has more than one frame. Only reads as far into the file as it
+/// takes to tell.
+pub fn is_animated(path: &str) -> bool {
+    match std::fs::File::open(path) {
+        Ok(file) => sniff(&mut BufReader::new(file)).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+fn sniff(r: &mut impl Read) -> io::Result<bool> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if magic.starts_with(b"GIF8") {
+        sniff_gif(r)
+    } else if magic == PNG_MAGIC {
+        sniff_apng(r)
+    } else {
+        let mut header = [0; 21];
+        header[..8].copy_from_slice(&magic);
+        r.read_exact(&mut header[8..])?;
+        Ok(is_animated_webp(&header))
+    }
+}
+
+fn skip(r: &mut impl Read, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut r.take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut b = [0];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+// The animation control chunk comes before the image data.
+fn sniff_apng(r: &mut impl Read) -> io::Result<bool> {
+    loop {
+        let mut header = [0; 8];
+        r.read_exact(&mut header)?;
+        let len = be_u32(&header, 0).unwrap();
+        match &header[4..] {
+            b"acTL" => {
+                let mut num_frames = [0; 4];
+                r.read_exact(&mut num_frames)?;
+                return Ok(u32::from_be_bytes(num_frames) > 1);
+            }
+            b"IDAT" | b"IEND" => return Ok(false),
+            // And its CRC.
+            _ => skip(r, u64::from(len) + 4)?,
+        }
+    }
+}
+
+fn skip_sub_blocks(r: &mut impl Read) -> io::Result<()> {
+    loop {
+        match read_u8(r)? {
+            0 => return Ok(()),
+            len => skip(r, u64::from(len))?,
+        }
+    }
+}
+
+// Walks the blocks of a GIF until its second image.
+fn sniff_gif(r: &mut impl Read) -> io::Result<bool> {
+    // The rest of the screen descriptor after its width, which may have a color table.
+    let mut screen = [0; 5];
+    r.read_exact(&mut screen)?;
+    if screen[2] & 0x80 != 0 {
+        skip(r, 3 << ((screen[2] & 0x07) + 1))?;
+    }
+
+    let mut images = 0;
+    loop {
+        match read_u8(r)? {
+            // Extension.
+            0x21 => {
+                read_u8(r)?;
+                skip_sub_blocks(r)?;
+            }
+            // Image descriptor.
+            0x2c => {
+                images += 1;
+                if images == 2 {
+                    return Ok(true);
+                }
+                let mut descriptor = [0; 9];
+                r.read_exact(&mut descriptor)?;
+                if descriptor[8] & 0x80 != 0 {
+                    skip(r, 3 << ((descriptor[8] & 0x07) + 1))?;
+                }
+                // LZW code size then the image data.
+                read_u8(r)?;
+                skip_sub_blocks(r)?;
+            }
+            _ => return Ok(false),
+        }
+    }
+}
+
+/// Decodes the frames of an animated image, or returns None for still images. The first frame
+/// is full size and the others are shrunk to fit in `size`.
+pub fn decode(path: &str, size: u32) -> R<Option<Vec<Frame>>> {
+    let _s = crate::stats::ScopedDuration::new("animation::decode");
+
+    let mut file = BufReader::new(std::fs::File::open(path).map_err(E::IoError)?);
+    if !sniff(&mut file).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0)).map_err(E::IoError)?;
+    file.read_to_end(&mut data).map_err(E::IoError)?;
+
+    let mut frames = Vec::new();
+    let mut push = |image: DynamicImage, delay: u32| {
+        let image = if frames.is_empty() {
+            image
+        } else {
+            image.thumbnail(size, size)
+        };
+        frames.push(Frame {
+            image,
+            delay_ms: delay_ms(delay),
+        });
+        frames.len() < MAX_FRAMES
+    };
+
+    if data.starts_with(b"GIF8") {
+        let decoder = ::image::gif::Decoder::new(&data[..]).map_err(E::ImageError)?;
+        for frame in decoder.into_frames() {
+            let frame = frame.map_err(E::ImageError)?;
+            let delay = u32::from(frame.delay().to_integer());
+            if !push(DynamicImage::ImageRgba8(frame.into_buffer()), delay) {
+                break;
+            }
+        }
+    } else if data.starts_with(&PNG_MAGIC) {
+        decode_apng(&data, &mut push)?;
+    } else {
+        let animation = webp::AnimDecoder::new(&data)
+            .decode()
+            .map_err(|e| E::CorruptData(format!("webp animation: {}", e)))?;
+        let mut start = 0;
+        for frame in &animation {
+            // Timestamps are when each frame ends.
+            let end = frame.get_time_ms();
+            let delay = (end - start).max(0) as u32;
+            start = end;
+            let (w, h, pixels) = (frame.width(), frame.height(), frame.get_image().to_vec());
+            let image = if frame.get_layout().is_alpha() {
+                RgbaImage::from_raw(w, h, pixels).map(DynamicImage::ImageRgba8)
+            } else {
+                ::image::RgbImage::from_raw(w, h, pixels).map(DynamicImage::ImageRgb8)
+            }
+            .ok_or_else(|| E::CorruptData(String::from("webp animation")))?;
+            if !push(image, delay) {
+                break;
+            }
+        }
+    }
+
+    Ok(Some(frames))
+}
+
+fn is_animated_webp(data: &[u8]) -> bool {
+    // RIFF header then the extended format chunk, whose flags have an animation bit.
+    data.len() > 20
+        && &data[..4] == b"RIFF"
+        && &data[8..12] == b"WEBP"
+        && &data[12..16] == b"VP8X"
+        && data[20] & 0x02 != 0
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let b = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let b = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn png_chunks(png: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut ret = Vec::new();
+    let mut rest = png.get(8..)?;
+    while rest.len() >= 12 {
+        let len = be_u32(rest, 0)? as usize;
+        let name = [rest[4], rest[5], rest[6], rest[7]];
+        ret.push((name, rest.get(8..8 + len)?));
+        if &name == b"IEND" {
+            break;
+        }
+        rest = rest.get(12 + len..)?;
+    }
+    Some(ret)
+}
+
+fn push_chunk(png: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(name);
+    png.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(name);
+    crc.update(data);
+    png.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+// An APNG frame control chunk.
+struct FrameControl {
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    delay_ms: u32,
+    dispose: u8,
+    blend: u8,
+}
+
+impl FrameControl {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let num = u32::from(be_u16(data, 20)?);
+        let den = match be_u16(data, 22)? {
+            0 => 100,
+            den => u32::from(den),
+        };
+        Some(FrameControl {
+            width: be_u32(data, 4)?,
+            height: be_u32(data, 8)?,
+            x: be_u32(data, 12)?,
+            y: be_u32(data, 16)?,
+            delay_ms: num * 1000 / den,
+            dispose: *data.get(24)?,
+            blend: *data.get(25)?,
+        })
+    }
+}
+
+fn decode_apng(png: &[u8], push: &mut dyn FnMut(DynamicImage, u32) -> bool) -> R<()> {
+    let corrupt = || E::CorruptData(String::from("apng"));
+
+    let chunks = png_chunks(png).ok_or_else(corrupt)?;
+
+    let ihdr = chunks
+        .iter()
+        .find(|(name, _)| name == b"IHDR")
+        .map(|(_, data)| *data)
+        .ok_or_else(corrupt)?;
+    let mut canvas = RgbaImage::new(
+        be_u32(ihdr, 0).ok_or_else(corrupt)?,
+        be_u32(ihdr, 4).ok_or_else(corrupt)?,
+    );
+
+    // Chunks every frame needs to decode.
+    let shared: Vec<&([u8; 4], &[u8])> = chunks
+        .iter()
+        .filter(|(name, _)| name == b"PLTE" || name == b"tRNS")
+        .collect();
+
+    // Frames are a control chunk followed by their data, the default image (IDAT) is only a
+    // frame if a control chunk comes before it.
+    let mut frames: Vec<(FrameControl, Vec<u8>)> = Vec::new();
+    for (name, data) in &chunks {
+        match name {
+            b"fcTL" => frames.push((FrameControl::parse(data).ok_or_else(corrupt)?, Vec::new())),
+            b"IDAT" => {
+                if let Some((_, frame_data)) = frames.last_mut() {
+                    frame_data.extend_from_slice(data);
+                }
+            }
+            b"fdAT" => {
+                if let Some((_, frame_data)) = frames.last_mut() {
+                    // After the sequence number.
+                    frame_data.extend_from_slice(data.get(4..).ok_or_else(corrupt)?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (i, (fc, data)) in frames.into_iter().enumerate() {
+        let mut frame_png = PNG_MAGIC.to_vec();
+        let mut frame_ihdr = ihdr.to_vec();
+        frame_ihdr[0..4].copy_from_slice(&fc.width.to_be_bytes());
+        frame_ihdr[4..8].copy_from_slice(&fc.height.to_be_bytes());
+        push_chunk(&mut frame_png, b"IHDR", &frame_ihdr);
+        for (name, data) in &shared {
+            push_chunk(&mut frame_png, name, data);
+        }
+        push_chunk(&mut frame_png, b"IDAT", &data);
+        push_chunk(&mut frame_png, b"IEND", &[]);
+
+        let frame = ::image::load_from_memory_with_format(&frame_png, ::image::ImageFormat::PNG)
+            .map_err(E::ImageError)?
+            .to_rgba();
+
+        if fc.x + fc.width > canvas.width() || fc.y + fc.height > canvas.height() {
+            return Err(corrupt());
+        }
+
+        // Dispose to previous, which the first frame treats as dispose to background.
+        let previous = if fc.dispose == 2 && i > 0 {
+            Some(canvas.sub_image(fc.x, fc.y, fc.width, fc.height).to_image())
+        } else {
+            None
+        };
+
+        for (x, y, &src) in frame.enumerate_pixels() {
+            let dst = canvas.get_pixel_mut(fc.x + x, fc.y + y);
+            if fc.blend == 0 {
+                *dst = src;
+            } else {
+                *dst = over(src, *dst);
+            }
+        }
+
+        if !push(DynamicImage::ImageRgba8(canvas.clone()), fc.delay_ms) {
+            break;
+        }
+
+        match (fc.dispose, previous) {
+            (_, Some(previous)) => {
+                canvas.copy_from(&previous, fc.x, fc.y);
+            }
+            (1, _) | (2, _) => {
+                for y in fc.y..fc.y + fc.height {
+                    for x in fc.x..fc.x + fc.width {
+                        canvas.put_pixel(x, y, ::image::Rgba([0; 4]));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Alpha compositing of `src` over `dst`.
+fn over(src: ::image::Rgba<u8>, dst: ::image::Rgba<u8>) -> ::image::Rgba<u8> {
+    let sa = u32::from(src[3]);
+    if sa == 255 {
+        return src;
+    }
+    let da = u32::from(dst[3]) * (255 - sa) / 255;
+    let a = sa + da;
+    if a == 0 {
+        return ::image::Rgba([0; 4]);
+    }
+    let mut ret = [0u8; 4];
+    for c in 0..3 {
+        ret[c] = ((u32::from(src[c]) * sa + u32::from(dst[c]) * da) / a) as u8;
+    }
+    ret[3] = a as u8;
+    ::image::Rgba(ret)
+}
+
+#[test]
+fn apng() {
+    use ::image::GenericImageView;
+
+    // A 2x2 canvas. The default image is not a frame, then a red frame and a half width blue
+    // frame blended over it.
+    let rgba = |w: u32, pixel: [u8; 4]| {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(w, 2, ::image::Rgba(pixel)))
+            .write_to(&mut png, ::image::ImageOutputFormat::PNG)
+            .unwrap();
+        let chunks = png_chunks(&png).unwrap();
+        let ihdr = chunks[0].1.to_vec();
+        let idat: Vec<u8> = chunks
+            .iter()
+            .filter(|(name, _)| name == b"IDAT")
+            .flat_map(|(_, data)| data.to_vec())
+            .collect();
+        (ihdr, idat)
+    };
+    let fctl = |seq: u32, w: u32, dispose: u8, blend: u8| {
+        let mut data = Vec::new();
+        for v in &[seq, w, 2, 0, 0] {
+            data.extend_from_slice(&v.to_be_bytes());
+        }
+        data.extend_from_slice(&[0, 5, 0, 100, dispose, blend]);
+        data
+    };
+
+    let (ihdr, green) = rgba(2, [0, 255, 0, 255]);
+    let (_, red) = rgba(2, [255, 0, 0, 255]);
+    let (_, blue) = rgba(1, [0, 0, 255, 255]);
+
+    let mut png = PNG_MAGIC.to_vec();
+    push_chunk(&mut png, b"IHDR", &ihdr);
+    push_chunk(&mut png, b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]);
+    push_chunk(&mut png, b"IDAT", &green);
+    push_chunk(&mut png, b"fcTL", &fctl(0, 2, 0, 0));
+    push_chunk(&mut png, b"fdAT", &[&[0, 0, 0, 1][..], &red].concat());
+    push_chunk(&mut png, b"fcTL", &fctl(2, 1, 0, 1));
+    push_chunk(&mut png, b"fdAT", &[&[0, 0, 0, 3][..], &blue].concat());
+    push_chunk(&mut png, b"IEND", &[]);
+
+    let dir = crate::store::test_path("apng");
+    let path = dir.join("a.png");
+    std::fs::write(&path, &png).unwrap();
+    let path = path.to_str().unwrap();
+
+    assert!(is_animated(path));
+    let frames = decode(path, 1).unwrap().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].delay_ms, 50);
+    assert_eq!(frames[0].image.dimensions(), (2, 2));
+    assert_eq!(
+        frames[0].image.get_pixel(1, 0),
+        ::image::Rgba([255, 0, 0, 255])
+    );
+    // Shrunk to fit the size.
+    assert_eq!(frames[1].image.dimensions(), (1, 1));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn sniff_prefix() {
+    let sniff = |data: &[u8]| sniff(&mut &data[..]).unwrap_or(false);
+
+    // A 1x1 GIF with a two color table, a frame delay and one image.
+    let mut gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00".to_vec();
+    gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+    gif.extend_from_slice(&[0x21, 0xf9, 4, 0, 10, 0, 0, 0]);
+    gif.extend_from_slice(&[0x2c, 0, 0, 0, 0, 1, 0, 1, 0, 0, 2, 2, 0x44, 0x01, 0]);
+    assert!(!sniff(&[&gif[..], &[0x3b]].concat()));
+    // Only the descriptor of the second image is read.
+    assert!(sniff(&[&gif[..], &[0x2c]].concat()));
+
+    let mut png = Vec::new();
+    DynamicImage::ImageRgba8(RgbaImage::new(1, 1))
+        .write_to(&mut png, ::image::ImageOutputFormat::PNG)
+        .unwrap();
+    assert!(!sniff(&png));
+
+    assert!(!sniff(b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0\0"));
+    assert!(sniff(b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0\x02"));
+}
@@ -0,0 +1,350 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Portable thumbnail cache archives: a header followed by one record per image holding its file
+// info, metadata and tiles. Tile indices are only meaningful in the exporting database so they are
+// reassigned on import. Metadata is in the encoding of the exporting database's schema version and
+// upgraded on import.
+
+use crate::database::{v2, v3, v4, v6, v7, Database, SCHEMA_VERSION};
+use crate::{File, Metadata, TileRef, E, R};
+use bincode::serialize_into;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+static MAGIC: [u8; 8] = *b"PIXCACHE";
+static ARCHIVE_VERSION: u32 = 1;
+
+// Archives come from elsewhere, a corrupt length mustn't make a read allocate more than this.
+static READ_LIMIT: u64 = 1 << 30;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    // Encoding of the `Metadata` in the archive.
+    schema: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Record<M = Metadata> {
+    Image {
+        file: File,
+        metadata: M,
+        tiles: Vec<(TileRef, Vec<u8>)>,
+    },
+    End,
+}
+
+fn read<T: DeserializeOwned>(r: &mut impl Read) -> R<T> {
+    bincode::config()
+        .limit(READ_LIMIT)
+        .deserialize_from(r)
+        .map_err(E::DecodeError)
+}
+
+/// Path prefix substitutions, e.g. for images that live under another home directory.
+#[derive(Debug, Default)]
+pub struct Rewrites(Vec<(String, String)>);
+
+impl Rewrites {
+    /// Parses `OLD=NEW` pairs.
+    pub fn parse<'a>(specs: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let mut ret = Vec::new();
+        for spec in specs {
+            let eq = spec.find('=')?;
+            ret.push((spec[..eq].to_owned(), spec[eq + 1..].to_owned()));
+        }
+        Some(Rewrites(ret))
+    }
+
+    // The first matching prefix wins.
+    fn apply(&self, path: &str) -> String {
+        for (old, new) in &self.0 {
+            if let Some(rest) = path.strip_prefix(old.as_str()) {
+                return format!("{}{}", new, rest);
+            }
+        }
+        path.to_owned()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveStats {
+    pub images: usize,
+    pub tiles: usize,
+    pub bytes: u64,
+    /// Images not exported because their tiles are missing, or not imported because they are
+    /// already cached.
+    pub skipped: usize,
+}
+
+/// Writes every up to date image in `db` to an archive at `path`.
+pub fn export(db: &Database, path: &Path, rewrites: &Rewrites) -> R<ArchiveStats> {
+    let _s = crate::stats::ScopedDuration::new("archive::export");
+
+    let mut ret = ArchiveStats::default();
+
+    let mut w = BufWriter::new(std::fs::File::create(path).map_err(E::IoError)?);
+
+    let header = Header {
+        magic: MAGIC,
+        version: ARCHIVE_VERSION,
+        schema: SCHEMA_VERSION,
+    };
+    serialize_into(&mut w, &header).map_err(E::EncodeError)?;
+
+    'files: for file in db.files()? {
+        let metadata = match db.get_metadata(&file)? {
+            Some(metadata) => metadata,
+            // Removed since it was listed.
+            None => continue,
+        };
+
+        let mut tiles = Vec::new();
+        for tile_ref in metadata.tile_refs() {
+            match db.get(*tile_ref)? {
+                Some(data) => tiles.push((*tile_ref, data.to_vec())),
+                None => {
+                    error!("export: missing tile {:?} of {:?}", tile_ref, file);
+                    ret.skipped += 1;
+                    continue 'files;
+                }
+            }
+        }
+
+        ret.images += 1;
+        ret.tiles += tiles.len();
+        ret.bytes += tiles.iter().map(|(_, data)| data.len() as u64).sum::<u64>();
+
+        let file = File {
+            path: rewrites.apply(&file.path),
+            ..file
+        };
+
+        let record = Record::Image {
+            file,
+            metadata,
+            tiles,
+        };
+        serialize_into(&mut w, &record).map_err(E::EncodeError)?;
+    }
+
+    serialize_into(&mut w, &Record::<Metadata>::End).map_err(E::EncodeError)?;
+    w.flush().map_err(E::IoError)?;
+
+    Ok(ret)
+}
+
+/// Adds the images in the archive at `path` that `db` doesn't have yet.
+pub fn import(db: &Database, path: &Path, rewrites: &Rewrites) -> R<ArchiveStats> {
+    let _s = crate::stats::ScopedDuration::new("archive::import");
+
+    let mut ret = ArchiveStats::default();
+
+    let mut r = BufReader::new(std::fs::File::open(path).map_err(E::IoError)?);
+
+    let header: Header = read(&mut r)?;
+    if header.magic != MAGIC {
+        return Err(E::UnsupportedArchive(String::from(
+            "not a pix cache archive",
+        )));
+    }
+    // Archives were introduced at schema 2.
+    if header.version != ARCHIVE_VERSION || !(2..=SCHEMA_VERSION).contains(&header.schema) {
+        return Err(E::UnsupportedArchive(format!(
+            "archive version {} schema {}, expected version {} schema up to {}",
+            header.version, header.schema, ARCHIVE_VERSION, SCHEMA_VERSION
+        )));
+    }
+
+    // Each schema that changed the encoding has its own decoder.
+    let schema = header.schema;
+    match schema {
+        2 => import_records::<v2::Metadata>(db, &mut r, schema, rewrites, &mut ret)?,
+        3 => import_records::<v3::Metadata>(db, &mut r, schema, rewrites, &mut ret)?,
+        4 | 5 => import_records::<v4::Metadata>(db, &mut r, schema, rewrites, &mut ret)?,
+        6 => import_records::<v6::Metadata>(db, &mut r, schema, rewrites, &mut ret)?,
+        7 => import_records::<v7::Metadata>(db, &mut r, schema, rewrites, &mut ret)?,
+        _ => import_records::<Metadata>(db, &mut r, schema, rewrites, &mut ret)?,
+    }
+
+    Ok(ret)
+}
+
+// Imports records with metadata encoded as `M` by schema `schema`.
+fn import_records<M: DeserializeOwned + Serialize>(
+    db: &Database,
+    r: &mut impl Read,
+    schema: u32,
+    rewrites: &Rewrites,
+    ret: &mut ArchiveStats,
+) -> R<()> {
+    while let Record::Image {
+        file,
+        metadata,
+        tiles,
+    } = read::<Record<M>>(r)?
+    {
+        let file = File {
+            path: rewrites.apply(&file.path),
+            ..file
+        };
+
+        if db.get_metadata(&file)?.is_some() {
+            ret.skipped += 1;
+            continue;
+        }
+
+        let metadata = match Database::upgrade_metadata(&file, schema, &metadata)? {
+            Some(metadata) => metadata,
+            // Thumbnailed again when it's next viewed.
+            None => {
+                ret.skipped += 1;
+                continue;
+            }
+        };
+
+        let id = db.reserve()?;
+
+        // Do before metadata write to prevent invalid metadata references.
+        for (tile_ref, data) in &tiles {
+            db.set(tile_ref.with_index(id), data)?;
+            ret.tiles += 1;
+            ret.bytes += data.len() as u64;
+        }
+
+        let mut metadata = metadata;
+        for thumb in metadata.all_thumbs_mut() {
+            for tile_ref in &mut thumb.tile_refs {
+                *tile_ref = tile_ref.with_index(id);
+            }
+        }
+
+        db.set_metadata(&file, &metadata)?;
+        ret.images += 1;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn rewrites() {
+    let rewrites = Rewrites::parse(vec!["/home/a=/home/b", "/mnt=/media"].into_iter()).unwrap();
+    assert_eq!(rewrites.apply("/home/a/x.jpg"), "/home/b/x.jpg");
+    assert_eq!(rewrites.apply("/mnt/x.jpg"), "/media/x.jpg");
+    assert_eq!(rewrites.apply("/srv/x.jpg"), "/srv/x.jpg");
+    assert!(Rewrites::parse(vec!["nope"].into_iter()).is_none());
+}
+
+#[test]
+fn round_trip() {
+    use crate::store::Backend;
+
+    let dir = crate::store::test_path("archive");
+
+    let image_path = dir.join("a.jpg");
+    std::fs::write(&image_path, b"image").unwrap();
+    let file = File::from_metadata(
+        image_path.to_str().unwrap().to_owned(),
+        &std::fs::metadata(&image_path).unwrap(),
+    );
+
+    let src = Database::open(Backend::Memory, "").unwrap();
+    let tile_ref = TileRef::new(crate::Pow2(3), src.reserve().unwrap(), 0);
+    src.set(tile_ref, b"tile").unwrap();
+    src.set_metadata(
+        &file,
+        &Metadata {
+            thumbs: vec![crate::Thumb {
+                img_size: [8, 8],
+                tile_refs: vec![tile_ref],
+                format: crate::TileFormat::Jpeg,
+                alpha: false,
+            }],
+            icc_converted: false,
+            animation: None,
+            duration_ms: None,
+        },
+    )
+    .unwrap();
+
+    let archive = dir.join("archive");
+    let rewrites = Rewrites::parse(vec!["/=/elsewhere/"].into_iter()).unwrap();
+    let stats = export(&src, &archive, &rewrites).unwrap();
+    assert_eq!((stats.images, stats.tiles, stats.bytes), (1, 1, 4));
+
+    // Index 0 is taken in the target.
+    let dst = Database::open(Backend::Memory, "").unwrap();
+    assert_eq!(dst.reserve().unwrap(), 0);
+
+    let stats = import(&dst, &archive, &Rewrites::default()).unwrap();
+    assert_eq!((stats.images, stats.skipped), (1, 0));
+
+    let imported = File {
+        path: format!("/elsewhere{}", file.path),
+        ..file.clone()
+    };
+    let metadata = dst.get_metadata(&imported).unwrap().unwrap();
+    let imported_ref = metadata.thumbs[0].tile_refs[0];
+    assert_eq!(imported_ref.index(), 1);
+    assert_eq!(&*dst.get(imported_ref).unwrap().unwrap(), b"tile");
+
+    let stats = import(&dst, &archive, &Rewrites::default()).unwrap();
+    assert_eq!((stats.images, stats.skipped), (0, 1));
+
+    // Archives of older schemas are upgraded.
+    let old = dir.join("old");
+    let mut w = std::fs::File::create(&old).unwrap();
+    let header = Header {
+        magic: MAGIC,
+        version: ARCHIVE_VERSION,
+        schema: 7,
+    };
+    serialize_into(&mut w, &header).unwrap();
+    let thumb = v4::Thumb {
+        img_size: [8, 8],
+        tile_refs: vec![tile_ref],
+        format: crate::TileFormat::Jpeg,
+        alpha: false,
+    };
+    let record = Record::Image {
+        file: file.clone(),
+        metadata: v7::Metadata {
+            thumbs: vec![thumb],
+            icc_converted: false,
+            animation: None,
+        },
+        tiles: vec![(tile_ref, b"tile".to_vec())],
+    };
+    serialize_into(&mut w, &record).unwrap();
+    serialize_into(&mut w, &Record::<v7::Metadata>::End).unwrap();
+    drop(w);
+
+    let dst = Database::open(Backend::Memory, "").unwrap();
+    let stats = import(&dst, &old, &Rewrites::default()).unwrap();
+    assert_eq!((stats.images, stats.skipped), (1, 0));
+    assert_eq!(dst.get_metadata(&file).unwrap().unwrap().duration_ms, None);
+
+    // A record claiming an enormous path is refused rather than allocated.
+    let mut w = std::fs::File::create(&old).unwrap();
+    serialize_into(&mut w, &header).unwrap();
+    serialize_into(&mut w, &(0u32, u64::MAX)).unwrap();
+    drop(w);
+    assert!(import(&dst, &old, &Rewrites::default()).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,145 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Memory budget of the thumbnailer. Jobs reserve the memory they are expected to need before
+// decoding and wait while it's used up, so a few huge images can't exhaust memory together.
+
+use futures::channel::oneshot;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub struct Budget {
+    // No limit if None.
+    limit: Option<u64>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    used: u64,
+
+    // Jobs are served in the order they asked so large ones aren't starved by small ones. Each
+    // is sent its bytes once they're taken from the budget.
+    waiting: VecDeque<(u64, oneshot::Sender<u64>)>,
+}
+
+impl State {
+    fn admit(&mut self, limit: u64) {
+        // Jobs that stopped waiting give their place up.
+        self.waiting.retain(|(_, tx)| !tx.is_canceled());
+
+        while let Some(&(bytes, _)) = self.waiting.front() {
+            if self.used + bytes > limit {
+                return;
+            }
+            let (bytes, tx) = self.waiting.pop_front().unwrap();
+            self.used += bytes;
+            if tx.send(bytes).is_err() {
+                self.used -= bytes;
+            }
+        }
+    }
+}
+
+/// Memory held by a job, returned to the budget when dropped.
+pub struct Reservation {
+    budget: Arc<Budget>,
+    bytes: u64,
+}
+
+impl Budget {
+    pub fn new(limit: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Resolves once the `estimate`d bytes fit in the budget, the estimate is skipped without a
+    /// limit. Jobs larger than the whole budget run alone. Waiting doesn't hold up the thread.
+    pub async fn reserve<F: FnOnce() -> u64>(self: &Arc<Self>, estimate: F) -> Reservation {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                return Reservation {
+                    budget: Arc::clone(self),
+                    bytes: 0,
+                }
+            }
+        };
+
+        let bytes = std::cmp::min(estimate(), limit);
+
+        let _s = crate::stats::ScopedDuration::new("Budget::reserve");
+
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back((bytes, tx));
+            state.admit(limit);
+            rx
+        };
+
+        // The budget outlives its waiters so the sender isn't dropped unsent.
+        let bytes = rx.await.unwrap();
+
+        Reservation {
+            budget: Arc::clone(self),
+            bytes,
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+        let mut state = self.budget.state.lock().unwrap();
+        state.used -= self.bytes;
+        state.admit(self.budget.limit.unwrap());
+    }
+}
+
+#[test]
+fn reserve() {
+    use futures::executor::block_on;
+    use futures::FutureExt;
+
+    let unlimited = Budget::new(None);
+    let _a = block_on(unlimited.reserve(|| unreachable!()));
+    let _b = block_on(unlimited.reserve(|| unreachable!()));
+
+    let budget = Budget::new(Some(100));
+    let a = block_on(budget.reserve(|| 60));
+
+    // Larger than the budget, runs once nothing else does.
+    let mut b = Box::pin(budget.reserve(|| 1000));
+    assert!((&mut b).now_or_never().is_none());
+
+    // Waits behind the larger one even though it would fit.
+    let mut c = Box::pin(budget.reserve(|| 10));
+    assert!((&mut c).now_or_never().is_none());
+
+    drop(a);
+    let b = b.now_or_never().unwrap();
+    assert!((&mut c).now_or_never().is_none());
+    drop(b);
+    let _c = c.now_or_never().unwrap();
+
+    // A job that stops waiting gives its place up.
+    let d = budget.reserve(|| 100).now_or_never();
+    assert!(d.is_none());
+    let _e = block_on(budget.reserve(|| 90));
+}
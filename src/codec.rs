@@ -0,0 +1,175 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Tile encodings. Raw tiles are a little endian u32 width and height followed by RGBA pixels.
+
+use crate::{TileFormat, E, R};
+use ::image::{DynamicImage, ImageOutputFormat, RgbImage, RgbaImage};
+
+static ZSTD_LEVEL: i32 = 3;
+
+/// The codec new tiles are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Jpeg,
+    Png,
+    WebP,
+    WebPLossless,
+    Raw,
+    RawZstd,
+}
+
+impl Codec {
+    pub const NAMES: &'static [&'static str] =
+        &["jpeg", "png", "webp", "webp_lossless", "raw", "raw_zstd"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "jpeg" => Some(Codec::Jpeg),
+            "png" => Some(Codec::Png),
+            "webp" => Some(Codec::WebP),
+            "webp_lossless" => Some(Codec::WebPLossless),
+            "raw" => Some(Codec::Raw),
+            "raw_zstd" => Some(Codec::RawZstd),
+            _ => None,
+        }
+    }
+
+    /// The tile format for an image. JPEG can't store transparency so those images get PNG.
+    pub fn format(self, alpha: bool) -> TileFormat {
+        match self {
+            Codec::Jpeg if alpha => TileFormat::Png,
+            Codec::Jpeg => TileFormat::Jpeg,
+            Codec::Png => TileFormat::Png,
+            Codec::WebP | Codec::WebPLossless => TileFormat::WebP,
+            Codec::Raw => TileFormat::Raw,
+            Codec::RawZstd => TileFormat::RawZstd,
+        }
+    }
+
+    /// Roughly the most bytes a pixel takes encoded.
+    pub fn bytes_per_pixel(self, alpha: bool) -> u64 {
+        match self.format(alpha) {
+            TileFormat::Jpeg => 1,
+            TileFormat::WebP if self == Codec::WebP => 1,
+            _ => 4,
+        }
+    }
+
+    /// Encodes a tile. `quality` (1-100) only applies to lossy codecs.
+    pub fn encode(self, tile: &RgbaImage, alpha: bool, quality: u8) -> R<Vec<u8>> {
+        let (w, h) = tile.dimensions();
+
+        Ok(match self.format(alpha) {
+            TileFormat::Jpeg | TileFormat::Png => {
+                let output_format = if self == Codec::Jpeg && !alpha {
+                    ImageOutputFormat::JPEG(quality)
+                } else {
+                    ImageOutputFormat::PNG
+                };
+                let mut image = DynamicImage::ImageRgba8(tile.clone());
+                if !alpha {
+                    image = DynamicImage::ImageRgb8(image.to_rgb());
+                }
+                let mut buf = Vec::with_capacity((2 * w * h) as usize);
+                image
+                    .write_to(&mut buf, output_format)
+                    .map_err(|e| E::TileEncodeError(e.to_string()))?;
+                buf
+            }
+            TileFormat::WebP => {
+                let encoder = webp::Encoder::from_rgba(tile, w, h);
+                let encoded = if self == Codec::WebPLossless {
+                    encoder.encode_lossless()
+                } else {
+                    encoder.encode(f32::from(quality))
+                };
+                encoded.to_vec()
+            }
+            TileFormat::Raw => raw(tile),
+            TileFormat::RawZstd => {
+                zstd::bulk::compress(&raw(tile), ZSTD_LEVEL).map_err(E::IoError)?
+            }
+        })
+    }
+}
+
+fn raw(tile: &RgbaImage) -> Vec<u8> {
+    let (w, h) = tile.dimensions();
+    let mut buf = Vec::with_capacity(8 + tile.len());
+    buf.extend_from_slice(&w.to_le_bytes());
+    buf.extend_from_slice(&h.to_le_bytes());
+    buf.extend_from_slice(tile);
+    buf
+}
+
+fn from_raw(data: &[u8]) -> R<DynamicImage> {
+    let corrupt = || E::CorruptData(String::from("raw tile"));
+    if data.len() < 8 {
+        return Err(corrupt());
+    }
+    let w = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    let h = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    RgbaImage::from_raw(w, h, data[8..].to_vec())
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(corrupt)
+}
+
+/// Decodes a tile written in `format`.
+pub fn decode(data: &[u8], format: TileFormat) -> R<DynamicImage> {
+    match format {
+        TileFormat::Jpeg => ::image::load_from_memory_with_format(data, ::image::ImageFormat::JPEG)
+            .map_err(E::ImageError),
+        TileFormat::Png => ::image::load_from_memory_with_format(data, ::image::ImageFormat::PNG)
+            .map_err(E::ImageError),
+        TileFormat::WebP => {
+            let corrupt = || E::CorruptData(String::from("webp tile"));
+            let image = webp::Decoder::new(data).decode().ok_or_else(corrupt)?;
+            let (w, h) = (image.width(), image.height());
+            if image.is_alpha() {
+                RgbaImage::from_raw(w, h, image.to_vec()).map(DynamicImage::ImageRgba8)
+            } else {
+                RgbImage::from_raw(w, h, image.to_vec()).map(DynamicImage::ImageRgb8)
+            }
+            .ok_or_else(corrupt)
+        }
+        TileFormat::Raw => from_raw(data),
+        TileFormat::RawZstd => {
+            let raw = zstd::stream::decode_all(data).map_err(E::IoError)?;
+            from_raw(&raw)
+        }
+    }
+}
+
+#[test]
+fn round_trip() {
+    use ::image::GenericImageView;
+
+    let mut tile = RgbaImage::new(5, 3);
+    tile.put_pixel(1, 1, ::image::Rgba([10, 20, 30, 40]));
+
+    for &name in Codec::NAMES {
+        let codec = Codec::from_name(name).unwrap();
+        for &alpha in &[false, true] {
+            let data = codec.encode(&tile, alpha, 90).unwrap();
+            let decoded = decode(&data, codec.format(alpha)).unwrap();
+            assert_eq!(decoded.dimensions(), (5, 3), "{} alpha={}", name, alpha);
+        }
+    }
+
+    let data = Codec::RawZstd.encode(&tile, true, 90).unwrap();
+    let decoded = decode(&data, TileFormat::RawZstd).unwrap().to_rgba();
+    assert_eq!(decoded.into_raw(), tile.into_raw());
+}
@@ -12,17 +12,154 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::orientation::Orientation;
+use crate::remote;
 use crate::stats;
-use crate::{File, Metadata, TileRef, E, R};
+use crate::store::{self, Backend, ThumbStore};
+use crate::{File, Metadata, TileFormat, TileRef, E, R};
 use bincode::{deserialize, serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
-static MAX_ID: &[u8] = b"_MAX_ID";
+pub use crate::store::Data;
+
+static LAST_GC: &[u8] = b"_LAST_GC";
+static SCHEMA_VERSION_KEY: &[u8] = b"_SCHEMA_VERSION";
+static RECHECK_KEY: &[u8] = b"_RECHECK";
 static METADATA_PREFIX: char = 'M';
-static TILE_PREFIX: char = 'T';
+static POINTER_PREFIX: char = 'P';
+static CONTENT_PREFIX: char = 'C';
+static ACCESS_PREFIX: char = 'A';
+static FAILURE_PREFIX: char = 'E';
+
+// Where failure records were kept before version 9, it's the sled store's free-list.
+static OLD_FAILURE_PREFIX: char = 'F';
+
+// Mixed into metadata keys. Frozen, format changes go through `MIGRATIONS` instead so existing
+// entries stay reachable.
+static KEY_VERSION: u32 = 2;
+
+// Version of the metadata encoding. Bump it and add a migration when changing `Metadata` or
+// `Thumb`, or when existing thumbnails need to be remade.
+pub static SCHEMA_VERSION: u32 = 9;
+
+// Databases without a schema version record were written by this version, the last one to
+// invalidate entries by changing `KEY_VERSION`.
+static UNVERSIONED_SCHEMA: u32 = 2;
+
+// The cache size is checked each time this fraction of `max_bytes` has been written.
+static EVICT_CHECK_FRACTION: u64 = 32;
+
+// `MIGRATIONS[i]` upgrades a database from version `UNVERSIONED_SCHEMA + i` to the next one.
+static MIGRATIONS: &[fn(&Database) -> R<()>] = &[
+    Database::migrate_tile_formats,
+    Database::migrate_alpha,
+    Database::migrate_orientation,
+    Database::migrate_icc,
+    Database::migrate_animation,
+    Database::migrate_duration,
+    Database::migrate_failures,
+];
+
+// Checks of the images behind existing entries that migrations leave for `Database::recheck` so
+// opening the database doesn't read every image. Entries whose images fail them are made again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Recheck {
+    // Version 5, the image is turned by its EXIF orientation.
+    Orientation,
+    // Version 6, the image has a color profile other than sRGB.
+    Icc,
+    // Version 7, the image is animated.
+    Animation,
+}
+
+impl Recheck {
+    fn remake(self, path: &str) -> bool {
+        match self {
+            Recheck::Orientation => !Orientation::read(path).is_identity(),
+            Recheck::Icc => crate::icc::profile(path).is_some_and(|p| !crate::icc::is_srgb(&p)),
+            Recheck::Animation => crate::animation::is_animated(path),
+        }
+    }
+}
+
+// Encodings of earlier schema versions.
+pub mod v2 {
+    use crate::TileRef;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Thumb {
+        pub img_size: [u32; 2],
+        pub tile_refs: Vec<TileRef>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Metadata {
+        pub thumbs: Vec<Thumb>,
+    }
+}
+
+pub mod v3 {
+    use crate::{TileFormat, TileRef};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Thumb {
+        pub img_size: [u32; 2],
+        pub tile_refs: Vec<TileRef>,
+        pub format: TileFormat,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Metadata {
+        pub thumbs: Vec<Thumb>,
+    }
+}
+
+// Also the encoding of version 5.
+pub mod v4 {
+    use crate::{TileFormat, TileRef};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Thumb {
+        pub img_size: [u32; 2],
+        pub tile_refs: Vec<TileRef>,
+        pub format: TileFormat,
+        pub alpha: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Metadata {
+        pub thumbs: Vec<Thumb>,
+    }
+}
+
+pub mod v6 {
+    #[derive(Serialize, Deserialize)]
+    pub struct Metadata {
+        pub thumbs: Vec<super::v4::Thumb>,
+        pub icc_converted: bool,
+    }
+}
+
+pub mod v7 {
+    #[derive(Serialize, Deserialize)]
+    pub struct Animation {
+        pub delays_ms: Vec<u32>,
+        pub frames: Vec<super::v4::Thumb>,
+    }
 
-// Mixed into all keys, bump when making breaking database format changes.
-static DB_VERSION: u32 = 2;
+    #[derive(Serialize, Deserialize)]
+    pub struct Metadata {
+        pub thumbs: Vec<super::v4::Thumb>,
+        pub icc_converted: bool,
+        pub animation: Option<Animation>,
+    }
+}
+
+// Bytes hashed from each of the start, middle and end of a file for its content key.
+static CONTENT_SAMPLE_BYTES: u64 = 64 << 10;
 
 #[derive(Debug)]
 struct Key(String);
@@ -32,7 +169,7 @@ impl Key {
         use std::collections::hash_map::DefaultHasher;
         use std::hash::{Hash, Hasher};
         let mut hasher = DefaultHasher::new();
-        let k = (&file.path, file.modified, file.file_size, DB_VERSION);
+        let k = (&file.path, file.modified, file.file_size, KEY_VERSION);
         k.hash(&mut hasher);
         hasher.finish()
     }
@@ -46,9 +183,91 @@ impl Key {
         ))
     }
 
-    fn for_thumb(tile_ref: TileRef) -> [u8; 9] {
-        let mut k: [u8; 9] = [TILE_PREFIX as u8; 9];
-        (&mut k[1..9]).copy_from_slice(&tile_ref.0.to_be_bytes());
+    // Points at the content key of the file's metadata.
+    fn for_pointer(file: &File) -> Key {
+        Self(format!(
+            "{}{}:{}",
+            POINTER_PREFIX,
+            file.path,
+            Self::hash_file(file)
+        ))
+    }
+
+    // Identifies the file by a hash of its size and contents so copies and moves share metadata.
+    // Only the start, middle and end are hashed so large videos aren't read in full, an edit
+    // elsewhere that keeps the size goes unnoticed.
+    fn for_content(file: &File) -> R<Key> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let _s = stats::ScopedDuration::new("Key::for_content");
+
+        let mut f = std::fs::File::open(&file.path).map_err(E::IoError)?;
+
+        let mut ctx = md5::Context::new();
+        ctx.consume(file.file_size.to_le_bytes());
+
+        let sample = CONTENT_SAMPLE_BYTES.min(file.file_size);
+        let mut buf = vec![0u8; sample as usize];
+        for offset in &[0, (file.file_size - sample) / 2, file.file_size - sample] {
+            f.seek(SeekFrom::Start(*offset)).map_err(E::IoError)?;
+            f.read_exact(&mut buf).map_err(E::IoError)?;
+            ctx.consume(&buf);
+        }
+
+        Ok(Self(format!("{}{:x}", CONTENT_PREFIX, ctx.compute())))
+    }
+
+    // Records why the file couldn't be thumbnailed. Keyed like metadata, so the file is tried
+    // again once it changes.
+    fn for_failure(file: &File) -> Key {
+        Self(format!(
+            "{}{}:{}",
+            FAILURE_PREFIX,
+            file.path,
+            Self::hash_file(file)
+        ))
+    }
+
+    // Recovers the path from a metadata, pointer or failure key.
+    fn path(k: &[u8]) -> Option<&str> {
+        let k = std::str::from_utf8(k).ok()?;
+        let k = k
+            .strip_prefix(METADATA_PREFIX)
+            .or_else(|| k.strip_prefix(POINTER_PREFIX))
+            .or_else(|| k.strip_prefix(FAILURE_PREFIX))?;
+        let (path, _hash) = k.split_at(k.rfind(':')?);
+        Some(path)
+    }
+
+    // The file at the metadata, pointer or failure key's path, if this is still its key.
+    fn current_file(k: &[u8]) -> Option<File> {
+        let path = Self::path(k)?;
+        let metadata = std::fs::metadata(path).ok()?;
+        let file = File::from_metadata(path.to_owned(), &metadata);
+        // All kinds of key only differ by prefix.
+        if Self::for_file(&file)[1..] == k[1..] {
+            Some(file)
+        } else {
+            None
+        }
+    }
+
+    // For log messages.
+    fn describe(k: &[u8]) -> String {
+        match Self::path(k) {
+            Some(path) => path.to_owned(),
+            None => String::from_utf8_lossy(k).into_owned(),
+        }
+    }
+
+    // Is this metadata, pointer or failure key still the key for the file at its path?
+    fn is_current(k: &[u8]) -> bool {
+        Self::current_file(k).is_some()
+    }
+
+    fn for_access_time(id: u64) -> [u8; 9] {
+        let mut k: [u8; 9] = [ACCESS_PREFIX as u8; 9];
+        k[1..9].copy_from_slice(&id.to_be_bytes());
         k
     }
 }
@@ -74,35 +293,548 @@ fn key_for_file() {
     );
 }
 
-// Wrap database types.
-pub struct Data(sled::IVec);
+#[test]
+fn key_path() {
+    assert_eq!(Key::path(b"M/here:5289273993602405726"), Some("/here"));
+    assert_eq!(Key::path(b"M/a:b/c:123"), Some("/a:b/c"));
+    assert_eq!(Key::path(b"E/bad.jpg:123"), Some("/bad.jpg"));
+    assert_eq!(Key::path(b"T12345678"), None);
+    assert_eq!(Key::path(b"M/no/hash"), None);
+}
 
-impl Deref for Data {
-    type Target = [u8];
-    fn deref(&self) -> &Self::Target {
-        self.0.deref()
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GcStats {
+    pub metadata_kept: usize,
+    pub metadata_removed: usize,
+    pub pointers_kept: usize,
+    pub pointers_removed: usize,
+    pub failures_removed: usize,
+    pub tiles_kept: usize,
+    pub tiles_removed: usize,
+    pub ids_freed: usize,
+    pub bytes_freed: u64,
+}
+
+/// What went wrong thumbnailing an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailureKind {
+    /// Not an image, or a format or feature the decoders don't support.
+    Unsupported,
+    /// The file couldn't be decoded.
+    Decode,
+    /// The external decoder failed.
+    ExternalDecoder,
+}
+
+/// Why a file couldn't be thumbnailed, remembered so it isn't tried again every launch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String,
+    /// Seconds since the unix epoch.
+    pub time: u64,
+}
+
+impl Failure {
+    /// The failure to record for `e`, None if it isn't the file's fault (a full disk, low memory, a
+    /// bug, ...) and the file should be tried again next time.
+    pub fn of(e: &E) -> Option<Self> {
+        use ::image::ImageError;
+
+        let kind = match e {
+            E::ImageError(ImageError::UnsupportedError(_))
+            | E::ImageError(ImageError::UnsupportedColor(_)) => FailureKind::Unsupported,
+            // Reading failed or there wasn't the memory to decode it, could work another time.
+            E::ImageError(ImageError::IoError(_))
+            | E::ImageError(ImageError::InsufficientMemory)
+            | E::ImageError(ImageError::DimensionError) => return None,
+            E::ImageError(_) | E::RawError(_) | E::SvgError(_) => FailureKind::Decode,
+            E::ExternalDecoderError(_) => FailureKind::ExternalDecoder,
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            message: e.to_string(),
+            time: Database::now(),
+        })
     }
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EvictStats {
+    pub images_evicted: usize,
+    pub bytes_freed: u64,
+    pub bytes_remaining: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FsckStats {
+    pub metadata_checked: usize,
+    pub tiles_checked: usize,
+    /// A description of each broken entry.
+    pub problems: Vec<String>,
+    pub metadata_removed: usize,
+}
+
+enum Store {
+    Local(Arc<dyn ThumbStore>),
+
+    // Another pix instance owns the database.
+    Remote(remote::Client),
+}
+
 pub struct Database {
-    db: sled::Db,
+    store: Store,
+
+    // Write metadata under content keys (with a pointer from the path key) rather than path keys.
+    content_keys: bool,
+
+    // Content keys of files whose lookup missed, by path key, so writing their metadata once
+    // they're thumbnailed doesn't hash them again.
+    hashed: Mutex<BTreeMap<String, String>>,
+
+    // Tile indices handed out by `reserve` in this process whose metadata hasn't been written yet.
+    // GC must leave their tiles alone.
+    reserved: Mutex<BTreeSet<u64>>,
+
+    // Indices whose metadata was written while a GC pass is running, which may already have
+    // scanned past it. None outside GC.
+    finished: Mutex<Option<BTreeSet<u64>>>,
+
+    // Access times (in seconds) of images viewed this session, keyed by tile index. Written back
+    // lazily by `flush_access_times`.
+    accessed: Mutex<BTreeMap<u64, u64>>,
+
+    // Cap on the size of the database, enforced as thumbnails are written.
+    max_bytes: Option<u64>,
+
+    // Tile bytes written since the size was last checked against `max_bytes`.
+    written: AtomicU64,
+
+    // Held while evicting, writes that land meanwhile count towards the next check.
+    evicting: Mutex<()>,
 }
 
 impl Database {
-    pub fn open(path: &str) -> R<Self> {
-        info!("database path: {}", path);
+    pub fn open(backend: Backend, path: &str) -> R<Self> {
+        info!("database path: {} ({:?})", path, backend);
+
+        match store::open(backend, path) {
+            Ok(store) => {
+                let db = Self::new(Store::Local(Arc::from(store)));
+                db.migrate()?;
+                Ok(db)
+            }
+            Err(e) if backend != Backend::Memory => {
+                // Most likely another instance holds the lock, forward to it if it's serving.
+                let socket_path = remote::socket_path(path);
+                match remote::Client::connect(&socket_path) {
+                    Ok(client) => {
+                        info!("Database in use, forwarding to {:?}", socket_path);
+                        Ok(Self::new(Store::Remote(client)))
+                    }
+                    Err(_) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn new(store: Store) -> Self {
+        Self {
+            store,
+            content_keys: false,
+            hashed: Mutex::new(BTreeMap::new()),
+            reserved: Mutex::new(BTreeSet::new()),
+            finished: Mutex::new(None),
+            accessed: Mutex::new(BTreeMap::new()),
+            max_bytes: None,
+            written: AtomicU64::new(0),
+            evicting: Mutex::new(()),
+        }
+    }
+
+    fn schema_version(&self) -> R<Option<u32>> {
+        match self.db().get(SCHEMA_VERSION_KEY)? {
+            Some(v) => std::str::from_utf8(&v)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Some)
+                .ok_or_else(|| E::CorruptData(String::from("_SCHEMA_VERSION"))),
+            None => Ok(None),
+        }
+    }
+
+    fn set_schema_version(&self, version: u32) -> R<()> {
+        self.db()
+            .set(SCHEMA_VERSION_KEY, format!("{}", version).as_bytes())?;
+        self.db().flush()
+    }
+
+    // Brings the database up to `SCHEMA_VERSION`, refusing to touch databases from newer versions
+    // of pix.
+    fn migrate(&self) -> R<()> {
+        let stored = self.schema_version()?;
+
+        let mut version = match stored {
+            Some(version) => version,
+            None => {
+                let empty = self.scan_metadata().next().is_none();
+                if empty {
+                    SCHEMA_VERSION
+                } else {
+                    UNVERSIONED_SCHEMA
+                }
+            }
+        };
+
+        if version > SCHEMA_VERSION {
+            return Err(E::SchemaTooNew(version));
+        }
+
+        let start = version;
+
+        while version < SCHEMA_VERSION {
+            let _s = stats::ScopedDuration::new("Database::migrate");
+            info!("Migrating database from version {}", version);
+
+            let migration = MIGRATIONS[(version - UNVERSIONED_SCHEMA) as usize];
+            migration(self)?;
+
+            version += 1;
+            self.set_schema_version(version)?;
+        }
+
+        // New databases, migrated ones have it written by their last migration.
+        if stored.is_none() && version == start {
+            self.set_schema_version(version)?;
+        }
+
+        Ok(())
+    }
+
+    // Version 3 records each thumbnail's tile format, all earlier tiles are JPEG.
+    fn migrate_tile_formats(&self) -> R<()> {
+        self.migrate_metadata(|metadata: v2::Metadata| {
+            let thumbs = metadata
+                .thumbs
+                .into_iter()
+                .map(|thumb| v3::Thumb {
+                    img_size: thumb.img_size,
+                    tile_refs: thumb.tile_refs,
+                    format: TileFormat::Jpeg,
+                })
+                .collect();
+            Some(v3::Metadata { thumbs })
+        })
+    }
 
-        let db = sled::Db::open(path).map_err(E::DatabaseError)?;
+    // Version 4 records which thumbnails have transparency, until then only PNG tiles had it.
+    fn migrate_alpha(&self) -> R<()> {
+        self.migrate_metadata(|metadata: v3::Metadata| {
+            let thumbs = metadata
+                .thumbs
+                .into_iter()
+                .map(|thumb| v4::Thumb {
+                    img_size: thumb.img_size,
+                    tile_refs: thumb.tile_refs,
+                    format: thumb.format,
+                    alpha: thumb.format == TileFormat::Png,
+                })
+                .collect();
+            Some(v4::Metadata { thumbs })
+        })
+    }
+
+    // Version 5 thumbnails are in EXIF display orientation. Entries of images that get turned are
+    // made again, the encoding is unchanged.
+    fn migrate_orientation(&self) -> R<()> {
+        self.defer(Recheck::Orientation)
+    }
+
+    // Version 6 converts images with ICC profiles to sRGB and records that it did. Entries of
+    // images with profiles other than sRGB are made again.
+    fn migrate_icc(&self) -> R<()> {
+        self.defer(Recheck::Icc)?;
+
+        self.migrate_metadata(|metadata: v4::Metadata| {
+            Some(v6::Metadata {
+                thumbs: metadata.thumbs,
+                icc_converted: false,
+            })
+        })
+    }
+
+    // Version 7 stores the frames of animated images. Entries of animated images are removed so
+    // they are made again.
+    fn migrate_animation(&self) -> R<()> {
+        self.defer(Recheck::Animation)?;
+
+        self.migrate_metadata(|metadata: v6::Metadata| {
+            Some(v7::Metadata {
+                thumbs: metadata.thumbs,
+                icc_converted: metadata.icc_converted,
+                animation: None,
+            })
+        })
+    }
+
+    // Version 8 records how long videos play. Videos couldn't be thumbnailed before so no entry
+    // has one.
+    fn migrate_duration(&self) -> R<()> {
+        let thumb = |thumb: v4::Thumb| crate::Thumb {
+            img_size: thumb.img_size,
+            tile_refs: thumb.tile_refs,
+            format: thumb.format,
+            alpha: thumb.alpha,
+        };
+
+        self.migrate_metadata(|metadata: v7::Metadata| {
+            Some(Metadata {
+                thumbs: metadata.thumbs.into_iter().map(thumb).collect(),
+                icc_converted: metadata.icc_converted,
+                animation: metadata.animation.map(|animation| crate::Animation {
+                    delays_ms: animation.delays_ms,
+                    frames: animation.frames.into_iter().map(thumb).collect(),
+                }),
+                duration_ms: None,
+            })
+        })
+    }
+
+    // Version 9 moves failure records off the prefix of the sled store's free-list. Free ids have
+    // no value, failures always do.
+    fn migrate_failures(&self) -> R<()> {
+        for kv in self.db().scan(&[OLD_FAILURE_PREFIX as u8]) {
+            let (k, v) = kv?;
+
+            if v.is_empty() || deserialize::<Failure>(&v).is_err() {
+                continue;
+            }
+
+            let mut new_key = k.to_vec();
+            new_key[0] = FAILURE_PREFIX as u8;
+            self.db().set(&new_key, &v)?;
+            self.db().remove(&k)?;
+        }
+
+        Ok(())
+    }
+
+    fn pending_rechecks(&self) -> R<Vec<Recheck>> {
+        match self.db().get(RECHECK_KEY)? {
+            Some(v) => deserialize(&v).map_err(E::DecodeError),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn defer(&self, recheck: Recheck) -> R<()> {
+        let mut pending = self.pending_rechecks()?;
+        if !pending.contains(&recheck) {
+            pending.push(recheck);
+        }
+        let encoded = serialize(&pending).map_err(E::EncodeError)?;
+        self.db().set(RECHECK_KEY, &encoded)
+    }
+
+    /// Runs the checks of existing entries that migrations deferred, removing the entries of images
+    /// that have to be thumbnailed again. Returns how many were removed.
+    pub fn recheck(&self) -> R<usize> {
+        // The instance serving the database does it.
+        if let Store::Remote(_) = &self.store {
+            return Ok(0);
+        }
+
+        let pending = self.pending_rechecks()?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let _s = stats::ScopedDuration::new("Database::recheck");
+
+        // Entries written meanwhile were made by this version.
+        *self.finished.lock().unwrap() = Some(BTreeSet::new());
+        let ret = self.remove_entries(|path| pending.iter().any(|recheck| recheck.remake(path)));
+        *self.finished.lock().unwrap() = None;
+
+        let ret = ret?;
+        self.db().remove(RECHECK_KEY)?;
+
+        info!("recheck: removed {} entries", ret);
+
+        Ok(ret)
+    }
+
+    /// Removes the entries of images at paths `remake` returns true for, their tiles are left for
+    /// GC. Content entries are only reachable through a path. Returns how many were removed.
+    fn remove_entries<F: Fn(&str) -> bool>(&self, remake: F) -> R<usize> {
+        let mut removed = 0;
+
+        let keys = self
+            .db()
+            .scan(&[METADATA_PREFIX as u8])
+            .chain(self.db().scan(&[POINTER_PREFIX as u8]));
+        for kv in keys {
+            let (k, v) = kv?;
+
+            // Content entries are shared by every copy, and every copy has the same contents.
+            let pointer = k[0] == POINTER_PREFIX as u8;
+            let metadata = if pointer {
+                match self.db().get(&v)? {
+                    Some(metadata) => metadata,
+                    None => continue,
+                }
+            } else {
+                v.to_vec().into()
+            };
+
+            let in_flight = deserialize::<Metadata>(&metadata)
+                .ok()
+                .and_then(|metadata| metadata.index())
+                .is_some_and(|id| self.is_in_flight(id));
+
+            if in_flight || !Key::path(&k).is_some_and(&remake) {
+                continue;
+            }
+
+            if pointer {
+                self.db().remove(&v)?;
+            }
+            self.db().remove(&k)?;
+            removed += 1;
+        }
+
+        if removed > 0 {
+            self.db().remove(LAST_GC)?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Brings `metadata` (of the image at `file`) from the encoding of schema `version` up to date
+    /// the way opening a database of that version would. None if the image has to be thumbnailed
+    /// again.
+    pub fn upgrade_metadata<T: serde::Serialize>(
+        file: &File,
+        version: u32,
+        metadata: &T,
+    ) -> R<Option<Metadata>> {
+        let db = Self::new(Store::Local(Arc::new(store::MemoryStore::default())));
+        db.set_schema_version(version)?;
+
+        let encoded = serialize(metadata).map_err(E::EncodeError)?;
+        db.db().set(&Key::for_file(file), &encoded)?;
+
+        db.migrate()?;
+        db.recheck()?;
+        db.get_metadata_keyed(file, false)
+    }
+
+    /// Rewrites every metadata entry encoded as `T` to `U` with `convert`. Entries it can't
+    /// convert (or that don't decode) are removed, their tiles are left for GC.
+    fn migrate_metadata<T, U, F>(&self, convert: F) -> R<()>
+    where
+        T: serde::de::DeserializeOwned,
+        U: serde::Serialize,
+        F: Fn(T) -> Option<U>,
+    {
+        let mut removed = 0;
+
+        for kv in self.scan_metadata() {
+            let (k, v) = kv?;
+
+            match deserialize::<T>(&v).ok().and_then(&convert) {
+                Some(metadata) => {
+                    let encoded = serialize(&metadata).map_err(E::EncodeError)?;
+                    self.db().set(&k, &encoded)?;
+                }
+                None => {
+                    self.db().remove(&k)?;
+                    removed += 1;
+                }
+            }
+        }
+
+        // Collect the orphaned tiles on the next run.
+        if removed > 0 {
+            info!("migration removed {} entries", removed);
+            self.db().remove(LAST_GC)?;
+        }
+
+        Ok(())
+    }
+
+    /// Key metadata written by this instance by file content so moved and copied files find it.
+    pub fn set_content_keys(&mut self, content_keys: bool) {
+        self.content_keys = content_keys;
+    }
+
+    /// Evict down to `max_bytes` as thumbnails are written. Databases forwarded to another instance
+    /// are kept to that instance's cap.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<u64>) {
+        self.max_bytes = max_bytes;
+    }
+
+    // Metadata stored under both path and content keys.
+    fn scan_metadata(&self) -> store::Iter<'_, (Data, Data)> {
+        Box::new(
+            self.db()
+                .scan(&[METADATA_PREFIX as u8])
+                .chain(self.db().scan(&[CONTENT_PREFIX as u8])),
+        )
+    }
+
+    pub fn is_remote(&self) -> bool {
+        match self.store {
+            Store::Local(_) => false,
+            Store::Remote(_) => true,
+        }
+    }
 
-        Ok(Self { db })
+    fn db(&self) -> &dyn ThumbStore {
+        match &self.store {
+            Store::Local(store) => &**store,
+            Store::Remote(_) => unreachable!("remote databases forward every public call"),
+        }
     }
 
     pub fn get_metadata(&self, file: &File) -> R<Option<Metadata>> {
+        self.get_metadata_keyed(file, self.content_keys)
+    }
+
+    /// Looks up metadata by path, then by content if `content_keys` is set.
+    pub fn get_metadata_keyed(&self, file: &File, content_keys: bool) -> R<Option<Metadata>> {
+        if let Store::Remote(client) = &self.store {
+            return client.get_metadata(file, content_keys);
+        }
+
         let _s = stats::ScopedDuration::new("Database::get_metadata");
 
-        let k = Key::for_file(file);
+        let mut v = self.db().get(&Key::for_file(file))?;
+
+        if v.is_none() {
+            if let Some(k) = self.db().get(&Key::for_pointer(file))? {
+                v = self.db().get(&k)?;
+            }
+        }
+
+        if v.is_none() && content_keys {
+            let k = Key::for_content(file)?;
+            v = self.db().get(&k)?;
 
-        if let Some(v) = self.db.get(k.as_ref()).map_err(E::DatabaseError)? {
+            if v.is_some() {
+                // Moved or copied, point the new path at the existing thumbnails.
+                self.db().set(&Key::for_pointer(file), &k)?;
+            } else {
+                self.hashed
+                    .lock()
+                    .unwrap()
+                    .insert(Key::for_file(file).0, k.0);
+            }
+        }
+
+        if let Some(v) = v {
             stats::record(
                 "metadata_size_bytes",
                 std::time::Duration::from_micros(v.len() as u64),
@@ -110,6 +842,10 @@ impl Database {
 
             let metadata: Metadata = deserialize(&*v).map_err(E::DecodeError)?;
 
+            if let Some(id) = metadata.index() {
+                self.touch(id);
+            }
+
             Ok(Some(metadata))
         } else {
             Ok(None)
@@ -117,9 +853,22 @@ impl Database {
     }
 
     pub fn set_metadata(&self, file: &File, metadata: &Metadata) -> R<()> {
-        let _s = stats::ScopedDuration::new("Database::set_metadata");
+        self.set_metadata_keyed(file, metadata, self.content_keys)
+    }
+
+    /// Writes metadata under the file's content key if `content_keys` is set, otherwise under its
+    /// path key.
+    pub fn set_metadata_keyed(
+        &self,
+        file: &File,
+        metadata: &Metadata,
+        content_keys: bool,
+    ) -> R<()> {
+        if let Store::Remote(client) = &self.store {
+            return client.set_metadata(file, metadata, content_keys);
+        }
 
-        let k = Key::for_file(file);
+        let _s = stats::ScopedDuration::new("Database::set_metadata");
 
         let encoded: Vec<u8> = serialize(metadata).map_err(E::EncodeError)?;
 
@@ -128,49 +877,998 @@ impl Database {
             std::time::Duration::from_micros(encoded.len() as u64),
         );
 
-        self.db
-            .insert(k.as_ref(), encoded)
-            .map_err(E::DatabaseError)?;
+        if content_keys {
+            let hashed = self.hashed.lock().unwrap().remove(&Key::for_file(file).0);
+            let k = match hashed {
+                Some(k) => Key(k),
+                None => Key::for_content(file)?,
+            };
+            self.db().set(&k, &encoded)?;
+            self.db().set(&Key::for_pointer(file), &k)?;
+
+            // Path keys take precedence, drop any left from before content keys were enabled.
+            self.db().remove(&Key::for_file(file))?;
+        } else {
+            self.db().set(&Key::for_file(file), &encoded)?;
+        }
+
+        if let Some(id) = metadata.index() {
+            self.touch(id);
+            self.finish(id);
+        }
+
+        self.check_size();
 
         Ok(())
     }
 
+    /// The recorded failure to thumbnail `file`, if it hasn't changed since.
+    pub fn get_failure(&self, file: &File) -> R<Option<Failure>> {
+        if let Store::Remote(client) = &self.store {
+            return client.get_failure(file);
+        }
+
+        match self.db().get(&Key::for_failure(file))? {
+            Some(v) => Ok(Some(deserialize(&*v).map_err(E::DecodeError)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_failure(&self, file: &File, failure: &Failure) -> R<()> {
+        if let Store::Remote(client) = &self.store {
+            return client.set_failure(file, failure);
+        }
+
+        let encoded: Vec<u8> = serialize(failure).map_err(E::EncodeError)?;
+        self.db().set(&Key::for_failure(file), &encoded)
+    }
+
+    /// Forgets every recorded failure so the files are tried again, returns how many there were.
+    pub fn clear_failures(&self) -> R<usize> {
+        if let Store::Remote(client) = &self.store {
+            return client.clear_failures();
+        }
+
+        let _s = stats::ScopedDuration::new("Database::clear_failures");
+
+        let mut ret = 0;
+        for kv in self.db().scan(&[FAILURE_PREFIX as u8]) {
+            let (k, _) = kv?;
+            self.db().remove(&k)?;
+            ret += 1;
+        }
+
+        Ok(ret)
+    }
+
     pub fn set(&self, tile_ref: TileRef, data: &[u8]) -> R<()> {
+        if let Store::Remote(client) = &self.store {
+            return client.set(tile_ref, data);
+        }
+
         let _s = stats::ScopedDuration::new("Database::set");
 
-        let k = Key::for_thumb(tile_ref);
-        self.db.insert(&k, data).map_err(E::DatabaseError)?;
+        self.written.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        self.db().set_tile(tile_ref, data)
+    }
+
+    /// Removes a tile no metadata references yet, e.g. of a thumbnail that failed midway.
+    pub fn remove(&self, tile_ref: TileRef) -> R<()> {
+        if let Store::Remote(client) = &self.store {
+            return client.remove(tile_ref);
+        }
+
+        self.db().remove_tile(tile_ref)?;
 
         Ok(())
     }
 
     pub fn get(&self, tile_ref: TileRef) -> R<Option<Data>> {
+        if let Store::Remote(client) = &self.store {
+            return client.get(tile_ref);
+        }
+
         let _s = stats::ScopedDuration::new("Database::get");
 
-        let k = Key::for_thumb(tile_ref);
-        if let Some(v) = self.db.get(k.as_ref()).map_err(E::DatabaseError)? {
-            Ok(Some(Data(v)))
-        } else {
-            Ok(None)
-        }
+        self.touch(tile_ref.index());
+
+        self.db().get_tile(tile_ref)
     }
 
-    // TODO: recycle old keys
-    pub fn reserve(&self, count: usize) -> u64 {
-        let max_id = self
-            .db
-            .get(MAX_ID)
-            .unwrap()
-            .map(|v| std::str::from_utf8(&v).unwrap().parse::<u64>().unwrap())
-            .unwrap_or(0);
+    /// Files with up to date metadata.
+    pub fn files(&self) -> R<Vec<File>> {
+        if let Store::Remote(client) = &self.store {
+            return client.files();
+        }
 
-        let next_max_id = max_id + count as u64;
-        assert!(next_max_id < (1u64 << 40));
+        let _s = stats::ScopedDuration::new("Database::files");
 
-        self.db
-            .insert(MAX_ID, format!("{}", next_max_id).as_bytes())
-            .unwrap();
+        let mut ret = Vec::new();
+        let keys = self
+            .db()
+            .scan(&[METADATA_PREFIX as u8])
+            .chain(self.db().scan(&[POINTER_PREFIX as u8]));
+        for kv in keys {
+            let (k, _) = kv?;
+            ret.extend(Key::current_file(&k));
+        }
+        ret.sort();
 
-        std::dbg!(max_id)
+        Ok(ret)
     }
+
+    /// Allocates a tile index for a new thumbnail, preferring indices freed by GC.
+    pub fn reserve(&self) -> R<u64> {
+        if let Store::Remote(client) = &self.store {
+            return client.reserve();
+        }
+
+        let id = self.db().reserve_id()?;
+
+        self.reserved.lock().unwrap().insert(id);
+
+        Ok(id)
+    }
+
+    /// Returns an index that has no tiles written under it to the free-list.
+    pub fn release(&self, id: u64) -> R<()> {
+        if let Store::Remote(client) = &self.store {
+            return client.release(id);
+        }
+
+        self.db().release_id(id)?;
+
+        self.reserved.lock().unwrap().remove(&id);
+
+        Ok(())
+    }
+
+    fn is_reserved(&self, id: u64) -> bool {
+        self.reserved.lock().unwrap().contains(&id)
+    }
+
+    // The metadata of a reserved index has been written, it's no longer in flight.
+    fn finish(&self, id: u64) {
+        if let Some(finished) = self.finished.lock().unwrap().as_mut() {
+            finished.insert(id);
+        }
+        self.reserved.lock().unwrap().remove(&id);
+    }
+
+    // Reserved, or finished since the running GC pass started.
+    fn is_in_flight(&self, id: u64) -> bool {
+        self.is_reserved(id)
+            || self
+                .finished
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|finished| finished.contains(&id))
+    }
+
+    fn touch(&self, id: u64) {
+        self.accessed.lock().unwrap().insert(id, Self::now());
+    }
+
+    fn is_accessed(&self, id: u64) -> bool {
+        self.accessed.lock().unwrap().contains_key(&id)
+    }
+
+    /// Persists the access times recorded this session.
+    pub fn flush_access_times(&self) -> R<()> {
+        if let Store::Remote(client) = &self.store {
+            return client.flush_access_times();
+        }
+
+        let _s = stats::ScopedDuration::new("Database::flush_access_times");
+
+        let accessed: Vec<(u64, u64)> = {
+            let accessed = self.accessed.lock().unwrap();
+            accessed.iter().map(|(&id, &t)| (id, t)).collect()
+        };
+
+        for (id, t) in accessed {
+            self.db().set(&Key::for_access_time(id), &t.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes everything out to disk.
+    pub fn flush(&self) -> R<()> {
+        // The instance serving the database flushes it.
+        if let Store::Remote(_) = &self.store {
+            return Ok(());
+        }
+
+        self.db().flush()
+    }
+
+    fn access_time(&self, id: u64) -> R<u64> {
+        let v = self.db().get(&Key::for_access_time(id))?;
+
+        // Images never viewed since access times were introduced are the oldest.
+        Ok(match v {
+            Some(v) if v.len() == 8 => {
+                let mut t = [0u8; 8];
+                t.copy_from_slice(&v);
+                u64::from_be_bytes(t)
+            }
+            _ => 0,
+        })
+    }
+
+    /// Removes the least recently viewed images until the database takes up no more than
+    /// `max_bytes` on disk. Images viewed or thumbnailed by this process go last.
+    pub fn evict(&self, max_bytes: u64) -> R<EvictStats> {
+        if let Store::Remote(client) = &self.store {
+            return client.evict(max_bytes);
+        }
+
+        let _s = stats::ScopedDuration::new("Database::evict");
+
+        self.flush_access_times()?;
+
+        let mut ret = EvictStats {
+            bytes_remaining: self.db().size_on_disk()?,
+            ..Default::default()
+        };
+
+        if ret.bytes_remaining <= max_bytes {
+            return Ok(ret);
+        }
+
+        struct Entry {
+            this_session: bool,
+            access_time: u64,
+            id: u64,
+            key: Data,
+            tile_refs: Vec<TileRef>,
+            bytes: u64,
+        }
+
+        let mut entries: Vec<Entry> = Vec::new();
+
+        // Bytes of keys and values, less than they take on disk.
+        let mut stored = 0;
+
+        for kv in self.db().tiles() {
+            let (_, bytes) = kv?;
+            stored += bytes;
+        }
+
+        for kv in self.scan_metadata() {
+            let (k, v) = kv?;
+
+            let bytes = (k.len() + v.len()) as u64;
+            stored += bytes;
+
+            // Undecodable metadata is left for GC.
+            let metadata: Metadata = match deserialize(&v) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            let id = match metadata.index() {
+                Some(id) => id,
+                None => continue,
+            };
+
+            entries.push(Entry {
+                this_session: self.is_accessed(id),
+                access_time: self.access_time(id)?,
+                id,
+                key: k,
+                tile_refs: metadata.tile_refs().cloned().collect(),
+                bytes,
+            });
+        }
+
+        // Assume each entry's share of the disk is in proportion to the bytes it stores.
+        let overhead = ret.bytes_remaining as f64 / std::cmp::max(stored, 1) as f64;
+
+        entries.sort_by_key(|entry| (entry.this_session, entry.access_time));
+
+        for entry in entries {
+            if ret.bytes_remaining <= max_bytes {
+                break;
+            }
+
+            if self.is_reserved(entry.id) {
+                continue;
+            }
+
+            let bytes = entry.bytes + self.remove_entry(&entry.key, entry.id, &entry.tile_refs)?;
+            let bytes = (bytes as f64 * overhead) as u64;
+
+            ret.images_evicted += 1;
+            ret.bytes_freed += bytes;
+            ret.bytes_remaining = ret.bytes_remaining.saturating_sub(bytes);
+        }
+
+        self.db().flush()?;
+
+        info!("evict: {:?}", ret);
+
+        Ok(ret)
+    }
+
+    // Evicts if enough has been written since the last check that the database may have outgrown
+    // `max_bytes`.
+    fn check_size(&self) {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return,
+        };
+
+        let slack = std::cmp::max(max_bytes / EVICT_CHECK_FRACTION, 1);
+        if self.written.load(Ordering::Relaxed) < slack {
+            return;
+        }
+
+        if let Ok(_evicting) = self.evicting.try_lock() {
+            self.written.store(0, Ordering::Relaxed);
+            if let Err(e) = self.evict(max_bytes) {
+                error!("evict: {:?}", e);
+            }
+        }
+    }
+
+    // Removes a metadata entry along with its tiles and releases its index. Returns the number of
+    // tile bytes freed.
+    fn remove_entry(&self, key: &[u8], id: u64, tile_refs: &[TileRef]) -> R<u64> {
+        // Remove metadata before its tiles so metadata never references missing tiles.
+        self.db().remove(key)?;
+
+        let mut bytes = 0;
+        for tile_ref in tile_refs {
+            bytes += self.db().remove_tile(*tile_ref)?;
+        }
+
+        self.db().remove(&Key::for_access_time(id))?;
+        self.accessed.lock().unwrap().remove(&id);
+
+        self.release(id)?;
+
+        Ok(bytes)
+    }
+
+    // Why the tiles of an entry can't be displayed, if they can't.
+    fn check_tiles(&self, metadata: &Metadata, stats: &mut FsckStats) -> R<Option<String>> {
+        for thumb in metadata.all_thumbs() {
+            for tile_ref in &thumb.tile_refs {
+                stats.tiles_checked += 1;
+
+                match self.db().get_tile(*tile_ref)? {
+                    None => return Ok(Some(format!("missing tile {:?}", tile_ref))),
+                    Some(data) => {
+                        if let Err(e) = crate::codec::decode(&data, thumb.format) {
+                            return Ok(Some(format!("undecodable tile {:?}: {}", tile_ref, e)));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Verifies every metadata entry decodes and that all the tiles it references exist and
+    /// decode. Broken entries are removed if `repair` is set so their images get thumbnailed again.
+    pub fn fsck(&self, repair: bool) -> R<FsckStats> {
+        if let Store::Remote(client) = &self.store {
+            return client.fsck(repair);
+        }
+
+        let _s = stats::ScopedDuration::new("Database::fsck");
+
+        let mut ret = FsckStats::default();
+
+        for kv in self.scan_metadata() {
+            let (k, v) = kv?;
+
+            ret.metadata_checked += 1;
+
+            let (metadata, problem) = match deserialize::<Metadata>(&v) {
+                Ok(metadata) => match self.check_tiles(&metadata, &mut ret)? {
+                    Some(problem) => (Some(metadata), problem),
+                    None => continue,
+                },
+                Err(e) => (None, format!("undecodable metadata: {}", e)),
+            };
+
+            ret.problems
+                .push(format!("{}: {}", Key::describe(&k), problem));
+
+            if !repair {
+                continue;
+            }
+
+            match metadata.as_ref().and_then(Metadata::index) {
+                // Tiles still being written by this process.
+                Some(id) if self.is_reserved(id) => continue,
+                Some(id) => {
+                    let tile_refs: Vec<TileRef> = metadata.unwrap().tile_refs().cloned().collect();
+                    self.remove_entry(&k, id, &tile_refs)?;
+                }
+                // Any tiles are orphans now, left for GC.
+                None => self.db().remove(&k)?,
+            }
+
+            ret.metadata_removed += 1;
+        }
+
+        // Checked after metadata so pointers to entries removed above go too.
+        for kv in self.db().scan(&[POINTER_PREFIX as u8]) {
+            let (k, v) = kv?;
+
+            if self.db().get(&v)?.is_some() {
+                continue;
+            }
+
+            ret.problems
+                .push(format!("{}: dangling pointer", Key::describe(&k)));
+
+            if repair {
+                self.db().remove(&k)?;
+            }
+        }
+
+        self.db().flush()?;
+
+        info!(
+            "fsck: checked {} entries, {} problems",
+            ret.metadata_checked,
+            ret.problems.len()
+        );
+
+        Ok(ret)
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("duration since unix epoch")
+            .as_secs()
+    }
+
+    /// Has it been at least `interval` seconds since the last completed GC pass?
+    pub fn gc_due(&self, interval: u64) -> R<bool> {
+        if let Store::Remote(client) = &self.store {
+            return client.gc_due(interval);
+        }
+
+        let last_gc = self
+            .db()
+            .get(LAST_GC)?
+            .and_then(|v| std::str::from_utf8(&v).ok()?.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        Ok(Self::now().saturating_sub(last_gc) >= interval)
+    }
+
+    /// Removes metadata and failure records for files that no longer exist (or have changed since
+    /// they were thumbnailed) and then every tile not referenced by the surviving metadata.
+    pub fn gc(&self) -> R<GcStats> {
+        if let Store::Remote(client) = &self.store {
+            return client.gc();
+        }
+
+        let _s = stats::ScopedDuration::new("Database::gc");
+
+        *self.finished.lock().unwrap() = Some(BTreeSet::new());
+        let ret = self.collect();
+        *self.finished.lock().unwrap() = None;
+
+        let ret = ret?;
+
+        info!("gc: {:?}", ret);
+
+        Ok(ret)
+    }
+
+    fn collect(&self) -> R<GcStats> {
+        let mut ret = GcStats::default();
+
+        // Tile indices referenced by surviving metadata.
+        let mut live: BTreeSet<u64> = BTreeSet::new();
+
+        for kv in self.db().scan(&[METADATA_PREFIX as u8]) {
+            let (k, v) = kv?;
+
+            if Key::is_current(&k) {
+                match deserialize::<Metadata>(&v) {
+                    Ok(metadata) => {
+                        live.extend(metadata.tile_refs().map(TileRef::index));
+                        ret.metadata_kept += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("gc: undecodable metadata {:?}: {:?}", Key::path(&k), e);
+                    }
+                }
+            }
+
+            // Remove metadata before its tiles so metadata never references missing tiles.
+            self.db().remove(&k)?;
+            ret.metadata_removed += 1;
+            ret.bytes_freed += (k.len() + v.len()) as u64;
+        }
+
+        // Content keyed metadata is live while any current path points at it.
+        let mut pointed: BTreeSet<Vec<u8>> = BTreeSet::new();
+
+        for kv in self.db().scan(&[POINTER_PREFIX as u8]) {
+            let (k, v) = kv?;
+
+            if Key::is_current(&k) && self.db().get(&v)?.is_some() {
+                pointed.insert(v.to_vec());
+                ret.pointers_kept += 1;
+                continue;
+            }
+
+            self.db().remove(&k)?;
+            ret.pointers_removed += 1;
+            ret.bytes_freed += (k.len() + v.len()) as u64;
+        }
+
+        for kv in self.db().scan(&[FAILURE_PREFIX as u8]) {
+            let (k, v) = kv?;
+
+            if Key::is_current(&k) {
+                continue;
+            }
+
+            self.db().remove(&k)?;
+            ret.failures_removed += 1;
+            ret.bytes_freed += (k.len() + v.len()) as u64;
+        }
+
+        for kv in self.db().scan(&[CONTENT_PREFIX as u8]) {
+            let (k, v) = kv?;
+
+            match deserialize::<Metadata>(&v) {
+                Ok(metadata) => {
+                    // Written after its pointer was scanned.
+                    let reserved = metadata.index().is_some_and(|id| self.is_in_flight(id));
+
+                    if pointed.contains(&*k) || reserved {
+                        live.extend(metadata.tile_refs().map(TileRef::index));
+                        ret.metadata_kept += 1;
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    error!("gc: undecodable metadata {:?}: {:?}", Key::describe(&k), e);
+                }
+            }
+
+            self.db().remove(&k)?;
+            ret.metadata_removed += 1;
+            ret.bytes_freed += (k.len() + v.len()) as u64;
+        }
+
+        // Indices whose tiles have all been removed.
+        let mut freed: BTreeSet<u64> = BTreeSet::new();
+
+        for kv in self.db().tiles() {
+            let (tile_ref, bytes) = kv?;
+
+            let index = tile_ref.index();
+            if live.contains(&index) || self.is_in_flight(index) {
+                ret.tiles_kept += 1;
+                continue;
+            }
+
+            self.db().remove_tile(tile_ref)?;
+            ret.tiles_removed += 1;
+            ret.bytes_freed += bytes;
+
+            freed.insert(index);
+        }
+
+        for id in freed {
+            // Could have been handed out again since its tiles were removed.
+            if !self.is_in_flight(id) {
+                self.db().remove(&Key::for_access_time(id))?;
+                self.release(id)?;
+                ret.ids_freed += 1;
+            }
+        }
+
+        self.db()
+            .set(LAST_GC, format!("{}", Self::now()).as_bytes())?;
+
+        self.db().flush()?;
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+fn test_metadata(index: u64) -> Metadata {
+    Metadata {
+        thumbs: vec![crate::Thumb {
+            img_size: [8, 8],
+            tile_refs: vec![TileRef::new(crate::Pow2(3), index, 0)],
+            format: crate::TileFormat::Jpeg,
+            alpha: false,
+        }],
+        icc_converted: false,
+        animation: None,
+        duration_ms: None,
+    }
+}
+
+#[test]
+fn gc_removes_stale_entries() {
+    let dir = store::test_path("gc");
+
+    let live_path = dir.join("live.jpg");
+    std::fs::write(&live_path, b"live").unwrap();
+    let live = File::from_metadata(
+        live_path.to_str().unwrap().to_owned(),
+        &std::fs::metadata(&live_path).unwrap(),
+    );
+
+    let gone = File {
+        path: dir.join("gone.jpg").to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+
+    let store: Arc<dyn ThumbStore> = Arc::new(store::MemoryStore::default());
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    for file in &[live, gone] {
+        let metadata = test_metadata(db.reserve().unwrap());
+        for tile_ref in metadata.tile_refs() {
+            db.set(*tile_ref, b"tile").unwrap();
+        }
+        db.set_metadata(file, &metadata).unwrap();
+    }
+    assert!(db.reserved.lock().unwrap().is_empty());
+
+    // An orphaned tile.
+    let orphan = TileRef::new(crate::Pow2(3), db.reserve().unwrap(), 0);
+    db.set(orphan, b"tile").unwrap();
+
+    // A new session so none of the above are reserved.
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    let stats = db.gc().unwrap();
+    assert_eq!(stats.metadata_kept, 1);
+    assert_eq!(stats.metadata_removed, 1);
+    assert_eq!(stats.tiles_kept, 1);
+    assert_eq!(stats.tiles_removed, 2);
+    assert_eq!(stats.ids_freed, 2);
+    assert!(!db.gc_due(60).unwrap());
+
+    // Freed indices are handed out before the high-water mark moves.
+    let mut ids = vec![db.reserve().unwrap(), db.reserve().unwrap()];
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+    assert_eq!(db.reserve().unwrap(), 3);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn failures_are_remembered() {
+    let dir = store::test_path("failures");
+
+    let bad_path = dir.join("bad.jpg");
+    std::fs::write(&bad_path, b"bad").unwrap();
+    let bad = File::from_metadata(
+        bad_path.to_str().unwrap().to_owned(),
+        &std::fs::metadata(&bad_path).unwrap(),
+    );
+
+    let gone = File {
+        path: dir.join("gone.jpg").to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+
+    assert_eq!(Failure::of(&E::Cancelled), None);
+    assert_eq!(
+        Failure::of(&E::ImageError(::image::ImageError::InsufficientMemory)),
+        None
+    );
+    let failure = Failure::of(&E::ImageError(::image::ImageError::FormatError(
+        String::from("bad"),
+    )))
+    .unwrap();
+    assert_eq!(failure.kind, FailureKind::Decode);
+
+    // Sled keeps its free-list among the records.
+    let db = Database::open(Backend::Sled, dir.join("db").to_str().unwrap()).unwrap();
+    let id = db.reserve().unwrap();
+    db.release(id).unwrap();
+
+    assert_eq!(db.get_failure(&bad).unwrap(), None);
+    db.set_failure(&bad, &failure).unwrap();
+    db.set_failure(&gone, &failure).unwrap();
+    assert_eq!(db.get_failure(&bad).unwrap(), Some(failure.clone()));
+
+    // Ids and failures don't get in each other's way.
+    assert_eq!(db.reserve().unwrap(), id);
+    db.release(id).unwrap();
+    assert_eq!(db.get_failure(&bad).unwrap(), Some(failure.clone()));
+
+    // Changed files are tried again.
+    let changed = File {
+        file_size: bad.file_size + 1,
+        ..bad.clone()
+    };
+    assert_eq!(db.get_failure(&changed).unwrap(), None);
+
+    assert_eq!(db.gc().unwrap().failures_removed, 1);
+    assert_eq!(db.get_failure(&bad).unwrap(), Some(failure.clone()));
+
+    assert_eq!(db.clear_failures().unwrap(), 1);
+    assert_eq!(db.get_failure(&bad).unwrap(), None);
+    assert_eq!(db.reserve().unwrap(), id);
+
+    // Version 8 kept them where the free-list is.
+    let old_key = [
+        &[OLD_FAILURE_PREFIX as u8][..],
+        &Key::for_failure(&bad)[1..],
+    ]
+    .concat();
+    db.db()
+        .set(&old_key, &serialize(&failure).unwrap())
+        .unwrap();
+    db.release(id).unwrap();
+    db.set_schema_version(8).unwrap();
+    db.migrate().unwrap();
+    assert_eq!(db.get_failure(&bad).unwrap(), Some(failure));
+    assert!(db.db().get(&old_key).unwrap().is_none());
+    assert_eq!(db.reserve().unwrap(), id);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn evict_least_recently_viewed() {
+    let store: Arc<dyn ThumbStore> = Arc::new(store::MemoryStore::default());
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    let files: Vec<File> = (0..3)
+        .map(|i| File {
+            path: format!("/{}", i),
+            ..Default::default()
+        })
+        .collect();
+
+    for file in &files {
+        let metadata = test_metadata(db.reserve().unwrap());
+        for tile_ref in metadata.tile_refs() {
+            db.set(*tile_ref, &[0u8; 1000]).unwrap();
+        }
+        db.set_metadata(file, &metadata).unwrap();
+    }
+    db.flush_access_times().unwrap();
+
+    // A new session so none of the above are reserved or accessed.
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    // Viewed this session so evicted last.
+    assert!(db.get_metadata(&files[0]).unwrap().is_some());
+
+    let size = store.size_on_disk().unwrap();
+    let stats = db.evict(size - 1).unwrap();
+    assert_eq!(stats.images_evicted, 1);
+    assert!(stats.bytes_freed > 1000);
+    assert!(db.get_metadata(&files[0]).unwrap().is_some());
+
+    let stats = db.evict(0).unwrap();
+    assert_eq!(stats.images_evicted, 2);
+    assert!(db.get_metadata(&files[0]).unwrap().is_none());
+
+    // No-op when under the limit.
+    assert_eq!(db.evict(u64::MAX).unwrap().images_evicted, 0);
+
+    // Enforced as thumbnails are written.
+    let mut db = Database::new(Store::Local(Arc::clone(&store)));
+    db.set_max_bytes(Some(2500));
+    for file in &files {
+        let metadata = test_metadata(db.reserve().unwrap());
+        for tile_ref in metadata.tile_refs() {
+            db.set(*tile_ref, &[0u8; 1000]).unwrap();
+        }
+        db.set_metadata(file, &metadata).unwrap();
+        assert!(store.size_on_disk().unwrap() <= 2500);
+    }
+    assert!(db.get_metadata(&files[2]).unwrap().is_some());
+}
+
+#[test]
+fn fsck_finds_broken_entries() {
+    let store: Arc<dyn ThumbStore> = Arc::new(store::MemoryStore::default());
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    let files: Vec<File> = (0..3)
+        .map(|i| File {
+            path: format!("/{}", i),
+            ..Default::default()
+        })
+        .collect();
+
+    let mut tile = Vec::new();
+    ::image::DynamicImage::new_rgb8(8, 8)
+        .write_to(&mut tile, ::image::ImageOutputFormat::JPEG(70))
+        .unwrap();
+
+    // Intact, truncated tile, missing tile.
+    for (file, data) in files.iter().zip(&[&tile[..], &tile[..10]]) {
+        let metadata = test_metadata(db.reserve().unwrap());
+        for tile_ref in metadata.tile_refs() {
+            db.set(*tile_ref, data).unwrap();
+        }
+        db.set_metadata(file, &metadata).unwrap();
+    }
+    db.set_metadata(&files[2], &test_metadata(db.reserve().unwrap()))
+        .unwrap();
+
+    store
+        .set(&Key::for_file(&File::default()), b"junk")
+        .unwrap();
+
+    // A new session so none of the above are reserved.
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    let stats = db.fsck(false).unwrap();
+    assert_eq!(stats.metadata_checked, 4);
+    assert_eq!(stats.problems.len(), 3);
+    assert_eq!(stats.metadata_removed, 0);
+
+    let stats = db.fsck(true).unwrap();
+    assert_eq!(stats.metadata_removed, 3);
+
+    assert!(db.get_metadata(&files[0]).unwrap().is_some());
+    assert!(db.get_metadata(&files[1]).unwrap().is_none());
+    assert!(db.get_metadata(&files[2]).unwrap().is_none());
+
+    let stats = db.fsck(false).unwrap();
+    assert_eq!(stats.metadata_checked, 1);
+    assert!(stats.problems.is_empty());
+}
+
+#[test]
+fn schema_version() {
+    let dir = store::test_path("schema");
+    let path = dir.to_str().unwrap();
+
+    let db = Database::open(Backend::Files, path).unwrap();
+    assert_eq!(db.schema_version().unwrap(), Some(SCHEMA_VERSION));
+
+    let file = File::default();
+    let metadata = test_metadata(db.reserve().unwrap());
+    db.set_metadata(&file, &metadata).unwrap();
+    db.db().set(b"M/junk:1", b"junk").unwrap();
+
+    db.migrate_metadata(|m: Metadata| Some(m)).unwrap();
+    assert_eq!(db.get_metadata(&file).unwrap(), Some(metadata));
+    assert!(db.db().get(b"M/junk:1").unwrap().is_none());
+    assert!(db.gc_due(60).unwrap());
+
+    db.set_schema_version(SCHEMA_VERSION + 1).unwrap();
+    drop(db);
+    match Database::open(Backend::Files, path) {
+        Err(E::SchemaTooNew(version)) => assert_eq!(version, SCHEMA_VERSION + 1),
+        _ => panic!("opened a newer database"),
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn content_keys_follow_copies() {
+    let dir = store::test_path("content");
+
+    let files: Vec<File> = ["a.jpg", "b.jpg"]
+        .iter()
+        .map(|name| {
+            let path = dir.join(name);
+            std::fs::write(&path, b"same").unwrap();
+            File::from_metadata(
+                path.to_str().unwrap().to_owned(),
+                &std::fs::metadata(&path).unwrap(),
+            )
+        })
+        .collect();
+
+    let store: Arc<dyn ThumbStore> = Arc::new(store::MemoryStore::default());
+    let mut db = Database::new(Store::Local(Arc::clone(&store)));
+    db.set_content_keys(true);
+
+    let metadata = test_metadata(db.reserve().unwrap());
+    for tile_ref in metadata.tile_refs() {
+        db.set(*tile_ref, b"tile").unwrap();
+    }
+
+    // The key hashed by a lookup that missed is reused once the file is thumbnailed.
+    assert_eq!(db.get_metadata(&files[0]).unwrap(), None);
+    assert_eq!(db.hashed.lock().unwrap().len(), 1);
+    db.set_metadata(&files[0], &metadata).unwrap();
+    assert!(db.hashed.lock().unwrap().is_empty());
+
+    // Found by content, then by the pointer that lookup left behind.
+    assert_eq!(db.get_metadata(&files[1]).unwrap(), Some(metadata.clone()));
+    assert_eq!(
+        db.get_metadata_keyed(&files[1], false).unwrap(),
+        Some(metadata)
+    );
+
+    // Edits that keep the size change the key if they're in a sampled block.
+    let content = |name: &str, edit: usize| {
+        let path = dir.join(name);
+        let mut data = vec![0u8; 1 << 20];
+        data[edit] = 1;
+        std::fs::write(&path, data).unwrap();
+        Key::for_content(&File::from_metadata(
+            path.to_str().unwrap().to_owned(),
+            &std::fs::metadata(&path).unwrap(),
+        ))
+        .unwrap()
+        .0
+    };
+    assert_ne!(content("c.jpg", 0), content("d.jpg", 1 << 19));
+    assert_ne!(content("c.jpg", 0), content("d.jpg", (1 << 20) - 1));
+    assert_eq!(content("c.jpg", 100_000), content("d.jpg", 200_000));
+
+    // A new session so none of the above are reserved.
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    std::fs::remove_file(&files[0].path).unwrap();
+    let stats = db.gc().unwrap();
+    assert_eq!(stats.pointers_removed, 1);
+    assert_eq!(stats.metadata_kept, 1);
+    assert_eq!(stats.tiles_removed, 0);
+
+    std::fs::remove_file(&files[1].path).unwrap();
+    let stats = db.gc().unwrap();
+    assert_eq!(stats.pointers_removed, 1);
+    assert_eq!(stats.metadata_removed, 1);
+    assert_eq!(stats.tiles_removed, 1);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn migrate_from_v2() {
+    let store: Arc<dyn ThumbStore> = Arc::new(store::MemoryStore::default());
+    let db = Database::new(Store::Local(Arc::clone(&store)));
+
+    // Unversioned databases are version 2, which had no tile formats.
+    let file = File::default();
+    let tile_ref = TileRef::new(crate::Pow2(3), 0, 0);
+    let v2 = serialize(&vec![([8u32, 8u32], vec![tile_ref])]).unwrap();
+    store.set(&Key::for_file(&file), &v2).unwrap();
+
+    // And one of an image that is turned for display.
+    let dir = store::test_path("migrate");
+    let path = dir.join("rotated.jpg");
+    std::fs::write(&path, crate::orientation::rotated_jpeg()).unwrap();
+    let rotated = File::from_metadata(
+        path.to_str().unwrap().to_owned(),
+        &std::fs::metadata(&path).unwrap(),
+    );
+    store.set(&Key::for_file(&rotated), &v2).unwrap();
+
+    db.migrate().unwrap();
+    assert_eq!(db.schema_version().unwrap(), Some(SCHEMA_VERSION));
+    assert_eq!(db.get_metadata(&file).unwrap(), Some(test_metadata(0)));
+
+    // Images are only looked at once the database is open.
+    assert!(db.get_metadata(&rotated).unwrap().is_some());
+    assert_eq!(
+        db.pending_rechecks().unwrap(),
+        vec![Recheck::Orientation, Recheck::Icc, Recheck::Animation]
+    );
+    assert_eq!(db.recheck().unwrap(), 1);
+    assert!(db.get_metadata(&rotated).unwrap().is_none());
+    assert!(db.pending_rechecks().unwrap().is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
 }
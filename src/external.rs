@@ -0,0 +1,210 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// External decoders. Files the image crate can't read (videos, ...) are handed to a command that
+// writes a still image, which is then thumbnailed like any other.
+
+use crate::{E, R};
+use ::image::DynamicImage;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static INPUT: &str = "{input}";
+static OUTPUT: &str = "{output}";
+
+static VIDEO_EXTENSIONS: &[&str] = &[
+    "3gp", "avi", "flv", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "ogv", "webm",
+    "wmv",
+];
+
+// Picks a representative frame from the start of the clip and prints the clip's length.
+static VIDEO_SCRIPT: &str = "ffmpeg -nostdin -loglevel error -i \"$1\" -vf thumbnail \
+                             -frames:v 1 -y \"$2\" && ffprobe -v error -show_entries \
+                             format=duration -of csv=p=0 \"$1\"";
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A command that turns files with some extensions into an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoder {
+    // Lower case, without the dot.
+    extensions: Vec<String>,
+
+    // The command and its arguments, `{input}` and `{output}` are replaced by the file's path and
+    // the path of the image to write.
+    argv: Vec<String>,
+}
+
+/// The image an external decoder made of a file.
+pub struct Decoded {
+    pub image: DynamicImage,
+
+    // How long the file plays, if the command printed it (in seconds) as its last line of output.
+    pub duration_ms: Option<u64>,
+}
+
+impl Decoder {
+    /// Parses `EXT[,EXT...]=COMMAND`. The command is split on whitespace, use a script for
+    /// anything that needs quoting.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let eq = spec.find('=')?;
+        let extensions: Vec<String> = spec[..eq]
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect();
+        let argv: Vec<String> = spec[eq + 1..]
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+
+        if extensions.is_empty() || argv.is_empty() || !argv.iter().any(|arg| arg.contains(OUTPUT))
+        {
+            return None;
+        }
+
+        Some(Self { extensions, argv })
+    }
+
+    /// Common video formats through ffmpeg and ffprobe.
+    pub fn video() -> Self {
+        Self {
+            extensions: VIDEO_EXTENSIONS
+                .iter()
+                .map(|&ext| String::from(ext))
+                .collect(),
+            argv: ["sh", "-c", VIDEO_SCRIPT, "sh", INPUT, OUTPUT]
+                .iter()
+                .map(|&arg| String::from(arg))
+                .collect(),
+        }
+    }
+
+    pub fn handles(&self, path: &str) -> bool {
+        Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+    }
+
+    /// Runs the command on `path` and reads the image it wrote.
+    pub fn decode(&self, path: &str) -> R<Decoded> {
+        let _s = crate::stats::ScopedDuration::new("external::decode");
+
+        let output = TempFile::new();
+        let output_path = output.0.to_str().expect("temp path");
+
+        let argv: Vec<String> = self
+            .argv
+            .iter()
+            .map(|arg| arg.replace(INPUT, path).replace(OUTPUT, output_path))
+            .collect();
+
+        let res = Command::new(&argv[0])
+            .args(&argv[1..])
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| E::ExternalDecoderError(format!("{}: {}", argv[0], e)))?;
+
+        if !res.status.success() {
+            return Err(E::ExternalDecoderError(format!(
+                "{} {}: {}",
+                argv[0],
+                res.status,
+                String::from_utf8_lossy(&res.stderr).trim()
+            )));
+        }
+
+        let image = ::image::open(&output.0).map_err(E::ImageError)?;
+
+        let duration_ms = String::from_utf8_lossy(&res.stdout)
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .and_then(|line| line.trim().parse::<f64>().ok())
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(|secs| (secs * 1000.0).round() as u64);
+
+        Ok(Decoded { image, duration_ms })
+    }
+}
+
+/// The decoder for `path`, the first of `decoders` that handles it.
+pub fn find<'a>(decoders: &'a [Decoder], path: &str) -> Option<&'a Decoder> {
+    decoders.iter().find(|decoder| decoder.handles(path))
+}
+
+// Removed when dropped, whether or not the command wrote it.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new() -> Self {
+        let n = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+        Self(std::env::temp_dir().join(format!("pix-external-{}-{}.png", std::process::id(), n)))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn decode() {
+    assert_eq!(Decoder::parse("mp4"), None);
+    assert_eq!(Decoder::parse("mp4=ffmpeg -i {input}"), None);
+
+    let decoder = Decoder::parse(".MP4, mov=cp {input} {output}").unwrap();
+    assert!(decoder.handles("/clips/a.mov"));
+    assert!(decoder.handles("/clips/a.Mp4"));
+    assert!(!decoder.handles("/clips/a.jpg"));
+    assert!(!decoder.handles("/clips/mp4"));
+
+    let dir = crate::store::test_path("external");
+    let input = dir.join("still.mp4");
+    DynamicImage::new_rgb8(3, 2)
+        .save_with_format(&input, ::image::ImageFormat::PNG)
+        .unwrap();
+    let input = input.to_str().unwrap();
+
+    let decoded = decoder.decode(input).unwrap();
+    assert_eq!(
+        ::image::GenericImageView::dimensions(&decoded.image),
+        (3, 2)
+    );
+    assert_eq!(decoded.duration_ms, None);
+
+    let decoder = Decoder {
+        extensions: vec![String::from("mp4")],
+        argv: [
+            "sh",
+            "-c",
+            "cp \"$1\" \"$2\" && echo 2.5",
+            "sh",
+            INPUT,
+            OUTPUT,
+        ]
+        .iter()
+        .map(|&arg| String::from(arg))
+        .collect(),
+    };
+    assert_eq!(decoder.decode(input).unwrap().duration_ms, Some(2500));
+
+    let failing = Decoder::parse("mp4=false {output}").unwrap();
+    assert!(failing.decode(input).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
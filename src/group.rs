@@ -18,9 +18,9 @@ use crate::stats::ScopedDuration;
 use crate::vec::*;
 use crate::view::View;
 use crate::Stopwatch;
-use crate::TileRef;
-use crate::R;
-use crate::{Metadata, MetadataState};
+use crate::{Metadata, MetadataState, Thumb};
+use crate::{TileFormat, TileRef};
+use crate::{E, R};
 use piston_window::{
     color, rectangle, DrawState, G2d, G2dTexture, G2dTextureContext, Texture, TextureSettings,
     Transformed,
@@ -28,6 +28,15 @@ use piston_window::{
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, VecDeque};
 
+#[derive(Debug, PartialEq)]
+enum Loaded {
+    All,
+    // Out of time.
+    Interrupted,
+    // A tile is missing or doesn't decode.
+    Broken(String),
+}
+
 #[derive(Debug)]
 pub struct Group {
     pub extents: [Vector2<u32>; 2],
@@ -107,8 +116,6 @@ impl Group {
 
         let target_size = view.target_size();
 
-        let texture_settings = TextureSettings::new();
-
         while let Some(coords) = self.cache_todo[p].pop_front() {
             let image = self.images.get_mut(&coords).unwrap();
 
@@ -129,38 +136,65 @@ impl Group {
 
             // Progressive resizing.
             let new_size = match new_size.cmp(&current_size) {
-                Ordering::Less => current_size - 1,
-                Ordering::Equal => {
-                    // Already loaded target size.
-                    continue;
-                }
-                Ordering::Greater => current_size + 1,
+                Ordering::Less => Some(current_size - 1),
+                Ordering::Equal => None,
+                Ordering::Greater => Some(current_size + 1),
             };
 
-            // Load new tiles.
-            for tile_ref in &metadata.thumbs[new_size].tile_refs {
-                // Already loaded.
-                if self.tiles.contains_key(tile_ref) {
-                    continue;
+            let mut loaded = Loaded::All;
+
+            if let Some(new_size) = new_size {
+                // Load new tiles.
+                let thumb = &metadata.thumbs[new_size];
+                loaded = Self::load_tiles(&mut self.tiles, thumb, db, texture_context, stopwatch);
+            } else if let Some(animation) = &metadata.animation {
+                // Already loaded target size, load the frames of visible animations.
+                if p == 0 && view.animates() {
+                    for frame in &animation.frames {
+                        loaded = Self::load_tiles(
+                            &mut self.tiles,
+                            frame,
+                            db,
+                            texture_context,
+                            stopwatch,
+                        );
+                        if loaded != Loaded::All {
+                            break;
+                        }
+                    }
+                } else {
+                    for frame in &animation.frames {
+                        for tile_ref in &frame.tile_refs {
+                            self.tiles.remove(tile_ref);
+                        }
+                    }
                 }
+            }
 
-                if stopwatch.done() {
+            match loaded {
+                Loaded::All => {}
+                Loaded::Interrupted => {
                     self.cache_todo[p].push_front(coords);
                     return false;
                 }
-
-                let data = db.get(*tile_ref).expect("db get").expect("missing tile");
-
-                let image = ::image::load_from_memory(&data).expect("load image");
-
-                // TODO: Would be great to move off thread.
-                let image =
-                    Texture::from_image(texture_context, &image.to_rgba(), &texture_settings)
-                        .expect("texture");
-
-                self.tiles.insert(*tile_ref, image);
+                // Thumbnail the image again rather than showing part of it.
+                Loaded::Broken(e) => {
+                    error!("load tiles of {:?}: {}", image.file, e);
+                    for tile_ref in metadata.tile_refs() {
+                        self.tiles.remove(tile_ref);
+                    }
+                    image.size = None;
+                    image.metadata = MetadataState::Missing;
+                    self.thumb_todo[p].push_back(coords);
+                    continue;
+                }
             }
 
+            let new_size = match new_size {
+                Some(new_size) => new_size,
+                None => continue,
+            };
+
             // Unload old tiles.
             for (j, thumb) in metadata.thumbs.iter().enumerate() {
                 if j == new_size {
@@ -178,34 +212,102 @@ impl Group {
         true
     }
 
-    pub fn make_thumbs(&mut self, p: usize, thumbnailer: &mut crate::Thumbnailer) -> bool {
-        loop {
-            if thumbnailer.is_full() {
-                return false;
+    // Loads the tiles of `thumb` that aren't loaded yet, until `stopwatch` runs out.
+    fn load_tiles(
+        tiles: &mut BTreeMap<TileRef, G2dTexture>,
+        thumb: &Thumb,
+        db: &Database,
+        texture_context: &mut G2dTextureContext,
+        stopwatch: &Stopwatch,
+    ) -> Loaded {
+        let texture_settings = TextureSettings::new();
+
+        for tile_ref in &thumb.tile_refs {
+            // Already loaded.
+            if tiles.contains_key(tile_ref) {
+                continue;
             }
 
-            if let Some(coords) = self.thumb_todo[p].pop_front() {
-                let image = self.images.get(&coords).unwrap();
-                if !thumbnailer.make_thumbs(image) {
-                    return false;
-                }
-            } else {
-                break true;
+            if stopwatch.done() {
+                return Loaded::Interrupted;
+            }
+
+            let image = match Self::load_tile(db, *tile_ref, thumb.format) {
+                Ok(image) => image,
+                Err(e) => return Loaded::Broken(format!("{:?}: {}", tile_ref, e)),
+            };
+
+            // TODO: Would be great to move off thread.
+            let image = Texture::from_image(texture_context, &image.to_rgba(), &texture_settings)
+                .expect("texture");
+
+            tiles.insert(*tile_ref, image);
+        }
+
+        Loaded::All
+    }
+
+    fn load_tile(db: &Database, tile_ref: TileRef, format: TileFormat) -> R<::image::DynamicImage> {
+        let data = db
+            .get(tile_ref)?
+            .ok_or_else(|| E::MissingData(format!("{:?}", tile_ref)))?;
+
+        crate::codec::decode(&data, format)
+    }
+
+    // Queues this group's images missing thumbnails until the thumbnailer's queue is full.
+    pub fn make_thumbs(
+        &mut self,
+        p: usize,
+        view: &View,
+        thumbnailer: &mut crate::Thumbnailer,
+    ) -> bool {
+        while !thumbnailer.is_full() {
+            let coords = match self.thumb_todo[p].pop_front() {
+                Some(coords) => coords,
+                None => return true,
+            };
+
+            let priority = crate::thumbnailer::Priority {
+                hidden: p != 0,
+                mouse_dist: vec2_square_len(view.mouse_dist(coords)) as u64,
+            };
+
+            thumbnailer.make_thumbs(self.images.get(&coords).unwrap(), priority);
+        }
+
+        false
+    }
+
+    /// Marks errored images missing so they're thumbnailed again, returns how many there were.
+    pub fn retry_errored(&mut self) -> usize {
+        let mut ret = 0;
+        for image in self.images.values_mut() {
+            if image.metadata == MetadataState::Errored {
+                image.metadata = MetadataState::Missing;
+                ret += 1;
             }
         }
+        ret
     }
 
     pub fn update_metadata(&mut self, coords: Vector2<u32>, metadata_res: R<Metadata>) {
         let image = self.images.get_mut(&coords).unwrap();
+
+        // A preview being replaced, its tiles are gone.
+        if let MetadataState::Some(metadata) = &image.metadata {
+            for tile_ref in metadata.tile_refs() {
+                self.tiles.remove(tile_ref);
+            }
+        }
+        image.size = None;
+
         image.metadata = match metadata_res {
             Ok(metadata) => {
                 self.cache_todo[0].push_front(coords);
                 MetadataState::Some(metadata)
             }
-            Err(e) => {
-                error!("make_thumb: {}", e);
-                MetadataState::Errored
-            }
+            Err(_) => MetadataState::Errored,
         };
     }
 
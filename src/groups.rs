@@ -93,6 +93,13 @@ impl Groups {
         }
     }
 
+    pub fn retry_errored(&mut self) -> usize {
+        self.groups
+            .iter_mut()
+            .map(|(_, group)| group.retry_errored())
+            .sum()
+    }
+
     pub fn regroup(&mut self, grid_size: Vector2<u32>) {
         let _s = ScopedDuration::new("Groups::regroup");
 
@@ -146,10 +153,10 @@ impl Groups {
         }
     }
 
-    pub fn make_thumbs(&mut self, thumbnailer: &mut Thumbnailer) {
+    pub fn make_thumbs(&mut self, view: &View, thumbnailer: &mut Thumbnailer) {
         for p in 0..2 {
             for (_, group) in &mut self.groups {
-                if !group.make_thumbs(p, thumbnailer) {
+                if !group.make_thumbs(p, view, thumbnailer) {
                     return;
                 }
             }
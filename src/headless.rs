@@ -0,0 +1,168 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Thumbnails images without opening a window, to pre-warm the cache.
+
+use crate::database::Database;
+use crate::image::Image;
+use crate::thumbnailer::{Priority, Thumbnailer};
+use crate::{File, MetadataState};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub cached: usize,
+    pub thumbnailed: usize,
+    /// Images that failed in an earlier run and haven't changed since.
+    pub skipped: usize,
+    /// Paths and errors of the images that couldn't be thumbnailed.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Thumbnails every image in `files` that isn't already cached, printing progress.
+pub fn run(db: &Database, files: Vec<Arc<File>>, mut thumbnailer: Thumbnailer) -> Summary {
+    let _s = crate::stats::ScopedDuration::new("headless::run");
+
+    let mut ret = Summary::default();
+
+    let mut todo: VecDeque<Image> = VecDeque::new();
+    for (i, file) in files.into_iter().enumerate() {
+        match db.get_metadata(&file) {
+            Ok(Some(_)) => ret.cached += 1,
+            Ok(None) => match db.get_failure(&file) {
+                Ok(Some(_)) => ret.skipped += 1,
+                _ => todo.push_back(Image::from(i, file, MetadataState::Missing)),
+            },
+            Err(e) => ret.failed.push((file.path.clone(), e.to_string())),
+        }
+    }
+
+    let total = todo.len();
+    println!(
+        "{} images to thumbnail, {} already cached, {} failed before.",
+        total, ret.cached, ret.skipped
+    );
+
+    // Images handed to the thumbnailer.
+    let mut pending = 0;
+
+    let start = Instant::now();
+    let mut last_report = start;
+
+    while !todo.is_empty() || pending > 0 {
+        while !thumbnailer.is_full() {
+            match todo.pop_front() {
+                Some(image) => {
+                    thumbnailer.make_thumbs(&image, Priority::default());
+                    pending += 1;
+                }
+                None => break,
+            }
+        }
+
+        thumbnailer.wait(Duration::from_millis(100));
+
+        for done in thumbnailer.recv() {
+            if done.preview {
+                continue;
+            }
+            pending -= 1;
+            match done.res {
+                Ok(_) => ret.thumbnailed += 1,
+                Err(e) => ret.failed.push((done.file.path.clone(), e.to_string())),
+            }
+        }
+
+        if last_report.elapsed() >= Duration::from_secs(1) {
+            last_report = Instant::now();
+            let finished = total - todo.len() - pending;
+            println!(
+                "{}/{} ({:.1} images/s)",
+                finished,
+                total,
+                finished as f64 / start.elapsed().as_secs_f64()
+            );
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    for (path, e) in &ret.failed {
+        println!("failed: {}: {}", path, e);
+    }
+    println!(
+        "Thumbnailed {} images in {:.1}s ({:.1} images/s), {} already cached, {} failed, {} skipped.",
+        ret.thumbnailed,
+        elapsed,
+        ret.thumbnailed as f64 / elapsed.max(0.001),
+        ret.cached,
+        ret.failed.len(),
+        ret.skipped
+    );
+
+    ret
+}
+
+#[test]
+fn thumbnails_dir() {
+    use crate::thumbnailer::{Options, Threads};
+
+    let dir = crate::store::test_path("headless");
+
+    let good = dir.join("good.png");
+    ::image::RgbImage::from_pixel(300, 200, ::image::Rgb([0, 255, 0]))
+        .save(&good)
+        .unwrap();
+    let broken = dir.join("broken.png");
+    std::fs::write(&broken, b"not a png").unwrap();
+
+    let files: Vec<Arc<File>> = [&good, &broken]
+        .iter()
+        .map(|path| {
+            Arc::new(File::from_metadata(
+                path.to_str().unwrap().to_owned(),
+                &std::fs::metadata(path).unwrap(),
+            ))
+        })
+        .collect();
+
+    let db = Arc::new(Database::open(crate::store::Backend::Memory, "").unwrap());
+    let thumbnailer = || {
+        let options = Options {
+            xdg: crate::xdg::Mode::Off,
+            previews: false,
+            ..Options::default()
+        };
+        Thumbnailer::new(Arc::clone(&db), Threads::new(1), options)
+    };
+
+    let summary = run(&db, files.clone(), thumbnailer());
+    assert_eq!(summary.thumbnailed, 1);
+    assert_eq!(summary.failed.len(), 1);
+    assert_eq!(summary.failed[0].0, files[1].path);
+
+    let metadata = db.get_metadata(&files[0]).unwrap().unwrap();
+    assert_eq!(metadata.thumbs.last().unwrap().img_size, [300, 200]);
+    assert!(metadata.tile_refs().all(|t| db.get(*t).unwrap().is_some()));
+    assert!(db.get_failure(&files[1]).unwrap().is_some());
+
+    // The next run has nothing left to do.
+    let summary = run(&db, files, thumbnailer());
+    assert_eq!((summary.cached, summary.skipped), (1, 1));
+    assert_eq!(summary.thumbnailed, 0);
+    assert!(summary.failed.is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,302 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Embedded ICC color profiles. Tiles are sRGB, so images in other color spaces (Display P3,
+// AdobeRGB, ...) are converted before tiling.
+
+use ::image::DynamicImage;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+static JPEG_MAGIC: [u8; 2] = [0xff, 0xd8];
+static PNG_MAGIC: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+static JPEG_ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+
+// The TIFF `InterColorProfile` tag.
+static TIFF_ICC_TAG: u16 = 34675;
+
+/// Reads the ICC profile embedded in the JPEG, PNG or TIFF image at `path`.
+pub fn profile(path: &str) -> Option<Vec<u8>> {
+    let _s = crate::stats::ScopedDuration::new("icc::profile");
+
+    let mut r = BufReader::new(std::fs::File::open(path).ok()?);
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).ok()?;
+    r.seek(SeekFrom::Start(0)).ok()?;
+
+    let res = if magic[..2] == JPEG_MAGIC {
+        jpeg_profile(&mut r)
+    } else if magic == PNG_MAGIC {
+        png_profile(&mut r)
+    } else if &magic[..4] == b"II*\0" || &magic[..4] == b"MM\0*" {
+        Ok(tiff_profile(&mut r))
+    } else {
+        Ok(None)
+    };
+
+    res.unwrap_or_else(|e| {
+        error!("icc profile of {}: {:?}", path, e);
+        None
+    })
+}
+
+fn be_u16(b: &[u8]) -> usize {
+    u16::from_be_bytes([b[0], b[1]]) as usize
+}
+
+fn be_u32(b: &[u8]) -> usize {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize
+}
+
+/// Is `profile` sRGB already? Going by its description, like most color managed applications, as
+/// the many sRGB profiles in circulation differ slightly.
+pub fn is_srgb(profile: &[u8]) -> bool {
+    description(profile).is_some_and(|desc| desc.contains("sRGB"))
+}
+
+// The `desc` tag, a `desc` (ICC v2) or `mluc` (v4, first record) text.
+fn description(profile: &[u8]) -> Option<String> {
+    let count = be_u32(profile.get(128..132)?);
+    let tag = (0..count).find_map(|i| {
+        let entry = profile.get(132 + 12 * i..144 + 12 * i)?;
+        if &entry[..4] != b"desc" {
+            return None;
+        }
+        let offset = be_u32(&entry[4..8]);
+        profile.get(offset..offset + be_u32(&entry[8..12]))
+    })?;
+
+    match tag.get(..4)? {
+        b"desc" => {
+            let len = be_u32(tag.get(8..12)?);
+            let text = tag.get(12..12 + len)?;
+            Some(String::from_utf8_lossy(text).into_owned())
+        }
+        b"mluc" => {
+            let record = tag.get(16..28)?;
+            let (len, offset) = (be_u32(&record[4..8]), be_u32(&record[8..12]));
+            let utf16: Vec<u16> = tag
+                .get(offset..offset + len)?
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            Some(String::from_utf16_lossy(&utf16))
+        }
+        _ => None,
+    }
+}
+
+// Large profiles are split over several APP2 segments, numbered from 1.
+fn jpeg_profile(r: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut soi = [0u8; 2];
+    r.read_exact(&mut soi)?;
+
+    let mut chunks: Vec<(u8, u8, Vec<u8>)> = Vec::new();
+
+    loop {
+        let mut marker = [0u8; 2];
+        r.read_exact(&mut marker)?;
+        if marker[0] != 0xff {
+            break;
+        }
+        match marker[1] {
+            // Fill byte.
+            0xff => continue,
+            // Standalone markers.
+            0x01 | 0xd0..=0xd7 => continue,
+            // Start of scan or end of image, the profile must come before.
+            0xda | 0xd9 => break,
+            _ => {}
+        }
+
+        let mut len = [0u8; 2];
+        r.read_exact(&mut len)?;
+        let len = be_u16(&len).saturating_sub(2);
+        let mut segment = vec![0u8; len];
+        r.read_exact(&mut segment)?;
+
+        if marker[1] == 0xe2 && segment.starts_with(JPEG_ICC_MARKER) {
+            let header = JPEG_ICC_MARKER.len();
+            if let Some(&[seq, count]) = segment.get(header..header + 2) {
+                chunks.push((seq, count, segment[header + 2..].to_vec()));
+            }
+        }
+    }
+
+    chunks.sort_by_key(|&(seq, _, _)| seq);
+    let complete = !chunks.is_empty()
+        && chunks
+            .iter()
+            .enumerate()
+            .all(|(i, &(seq, count, _))| seq as usize == i + 1 && count as usize == chunks.len());
+    if !complete {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        chunks.into_iter().flat_map(|(_, _, data)| data).collect(),
+    ))
+}
+
+// The zlib compressed iCCP chunk.
+fn png_profile(r: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+
+    loop {
+        let mut header = [0u8; 8];
+        r.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let name = &header[4..8];
+
+        match name {
+            b"iCCP" => {
+                let mut data = vec![0u8; len];
+                r.read_exact(&mut data)?;
+
+                // Profile name, compression method (always 0, zlib), compressed profile.
+                let nul = match data.iter().position(|&b| b == 0) {
+                    Some(nul) => nul,
+                    None => return Ok(None),
+                };
+                return Ok(data
+                    .get(nul + 2..)
+                    .and_then(|compressed| inflate::inflate_bytes_zlib(compressed).ok()));
+            }
+            b"IDAT" | b"IEND" => return Ok(None),
+            _ => {
+                // Skip the data and CRC.
+                std::io::copy(&mut r.take(len as u64 + 4), &mut std::io::sink())?;
+            }
+        }
+    }
+}
+
+fn tiff_profile<R: std::io::BufRead + Seek>(r: &mut R) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_from_container(r).ok()?;
+    let field = exif.get_field(
+        exif::Tag(exif::Context::Tiff, TIFF_ICC_TAG),
+        exif::In::PRIMARY,
+    )?;
+    match &field.value {
+        exif::Value::Undefined(data, _) | exif::Value::Byte(data) => Some(data.clone()),
+        _ => None,
+    }
+}
+
+/// Converts `image` from the color space described by `profile` to sRGB. Returns false, leaving
+/// `image` alone, if the profile can't be used (e.g. it's for grayscale or CMYK).
+pub fn to_srgb(image: &mut DynamicImage, profile: &[u8]) -> bool {
+    let _s = crate::stats::ScopedDuration::new("icc::to_srgb");
+
+    let input = match qcms::Profile::new_from_slice(profile, false) {
+        Some(input) => input,
+        None => return false,
+    };
+    let mut output = qcms::Profile::new_sRGB();
+    output.precache_output_transform();
+
+    let alpha = matches!(
+        image,
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) | DynamicImage::ImageBgra8(_)
+    );
+    let data_type = if alpha {
+        qcms::DataType::RGBA8
+    } else {
+        qcms::DataType::RGB8
+    };
+
+    let transform = match qcms::Transform::new(&input, &output, data_type, qcms::Intent::Perceptual)
+    {
+        Some(transform) => transform,
+        None => return false,
+    };
+
+    if alpha {
+        let mut rgba = image.to_rgba();
+        transform.apply(&mut rgba);
+        *image = DynamicImage::ImageRgba8(rgba);
+    } else {
+        let mut rgb = image.to_rgb();
+        transform.apply(&mut rgb);
+        *image = DynamicImage::ImageRgb8(rgb);
+    }
+
+    true
+}
+
+#[test]
+fn jpeg_profile_chunks() {
+    // Two APP2 chunks out of order, around an unrelated APP1.
+    let mut jpeg = JPEG_MAGIC.to_vec();
+    let mut segment = |marker: u8, body: &[u8]| {
+        jpeg.extend_from_slice(&[0xff, marker]);
+        jpeg.extend_from_slice(&(2 + body.len() as u16).to_be_bytes());
+        jpeg.extend_from_slice(body);
+    };
+    segment(0xe2, b"ICC_PROFILE\0\x02\x02world");
+    segment(0xe1, b"Exif\0\0");
+    segment(0xe2, b"ICC_PROFILE\0\x01\x02hello ");
+    jpeg.extend_from_slice(&[0xff, 0xda, 0, 2, 0xff, 0xd9]);
+
+    assert_eq!(
+        jpeg_profile(&mut &jpeg[..]).unwrap(),
+        Some(b"hello world".to_vec())
+    );
+
+    // A chunk is missing.
+    let truncated = [
+        &JPEG_MAGIC[..],
+        b"\xff\xe2\x00\x12ICC_PROFILE\0\x01\x02hi\xff\xd9",
+    ]
+    .concat();
+    assert_eq!(jpeg_profile(&mut &truncated[..]).unwrap(), None);
+
+    let mut image = DynamicImage::new_rgb8(1, 1);
+    assert!(!to_srgb(&mut image, b"not a profile"));
+}
+
+#[test]
+fn srgb_profiles() {
+    // A header and a tag table with just a description.
+    let profile = |tag: &[u8]| {
+        let mut profile = vec![0u8; 128];
+        profile.extend_from_slice(&1u32.to_be_bytes());
+        profile.extend_from_slice(b"desc");
+        profile.extend_from_slice(&144u32.to_be_bytes());
+        profile.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        profile.extend_from_slice(tag);
+        profile
+    };
+
+    let v2 = |text: &str| {
+        let mut tag = b"desc\0\0\0\0".to_vec();
+        tag.extend_from_slice(&(text.len() as u32).to_be_bytes());
+        tag.extend_from_slice(text.as_bytes());
+        profile(&tag)
+    };
+    assert!(is_srgb(&v2("sRGB IEC61966-2.1")));
+    assert!(!is_srgb(&v2("Display P3")));
+
+    let mut mluc = b"mluc\0\0\0\0".to_vec();
+    mluc.extend_from_slice(&1u32.to_be_bytes());
+    mluc.extend_from_slice(&12u32.to_be_bytes());
+    mluc.extend_from_slice(b"enUS");
+    mluc.extend_from_slice(&8u32.to_be_bytes());
+    mluc.extend_from_slice(&28u32.to_be_bytes());
+    mluc.extend("sRGB".encode_utf16().flat_map(|c| c.to_be_bytes().to_vec()));
+    assert!(is_srgb(&profile(&mluc)));
+
+    assert!(!is_srgb(b"not a profile"));
+}
@@ -14,9 +14,18 @@
 
 use crate::view::View;
 use crate::{File, Metadata, MetadataState, TileRef};
-use piston_window::{DrawState, G2d, G2dTexture};
+use piston_window::{ellipse, DrawState, Ellipse, G2d, G2dTexture, Polygon, Transformed};
 use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Instant;
+
+// Smaller squares are too busy for a play badge.
+static BADGE_MIN_ZOOM: f64 = 32.0;
+
+lazy_static! {
+    // Animations play from here.
+    static ref START: Instant = Instant::now();
+}
 
 #[derive(Debug)]
 pub struct Image {
@@ -61,11 +70,49 @@ impl Image {
     ) -> bool {
         if let Some(n) = self.size {
             let metadata = self.get_metadata().expect("Image::get_metadata");
-            let thumb = &metadata.thumbs[n];
+            let mut thumb = &metadata.thumbs[n];
+
+            // Frames that aren't loaded yet are skipped.
+            if let Some(animation) = metadata.animation.as_ref().filter(|_| view.animates()) {
+                let time_ms = START.elapsed().as_millis() as u64;
+                if let Some(frame) = animation.frame_at(time_ms).checked_sub(1) {
+                    let frame = &animation.frames[frame];
+                    if frame.tile_refs.iter().all(|t| tiles.contains_key(t)) {
+                        thumb = frame;
+                    }
+                }
+            }
+
             thumb.draw(trans, view, tiles, draw_state, g);
+
+            if metadata.duration_ms.is_some() && view.zoom >= BADGE_MIN_ZOOM {
+                Self::draw_play_badge(trans, view.zoom, draw_state, g);
+            }
+
             true
         } else {
             false
         }
     }
+
+    // A play button over the middle of a video's grid square.
+    fn draw_play_badge(trans: [[f64; 3]; 2], zoom: f64, draw_state: &DrawState, g: &mut G2d) {
+        let radius = f64::min(zoom / 8.0, 24.0);
+        let trans = trans.trans(zoom / 2.0, zoom / 2.0);
+
+        Ellipse::new([0.0, 0.0, 0.0, 0.6]).draw(
+            ellipse::circle(0.0, 0.0, radius),
+            draw_state,
+            trans,
+            g,
+        );
+
+        let r = radius / 2.0;
+        Polygon::new([1.0, 1.0, 1.0, 0.9]).draw(
+            &[[-0.6 * r, -r], [-0.6 * r, r], [r, 0.0]],
+            draw_state,
+            trans,
+            g,
+        );
+    }
 }
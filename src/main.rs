@@ -21,19 +21,34 @@ extern crate failure;
 #[macro_use]
 extern crate lazy_static;
 
+mod animation;
+mod archive;
+mod budget;
+mod codec;
 mod database;
+mod external;
 mod group;
 mod groups;
+mod headless;
+mod icc;
 mod image;
+mod orientation;
+mod raw;
+mod remote;
+mod resample;
 mod stats;
+mod store;
+mod strips;
+mod svg;
 mod thumbnailer;
 mod vec;
 mod view;
+mod xdg;
 
 use crate::groups::Groups;
 use crate::stats::ScopedDuration;
 use boolinator::Boolinator;
-use clap::Arg;
+use clap::{Arg, SubCommand};
 use piston_window::*;
 use rayon::prelude::*;
 use std::collections::BTreeMap;
@@ -55,8 +70,47 @@ pub enum E {
     #[fail(display = "missing data for key {:?}", 0)]
     MissingData(String),
 
+    #[fail(display = "corrupt data for key {:?}", 0)]
+    CorruptData(String),
+
+    #[fail(display = "tile id space exhausted")]
+    IdSpaceExhausted,
+
     #[fail(display = "image error: {:?}", 0)]
     ImageError(::image::ImageError),
+
+    #[fail(display = "tile encode error: {}", 0)]
+    TileEncodeError(String),
+
+    #[fail(display = "io error: {:?}", 0)]
+    IoError(std::io::Error),
+
+    #[fail(display = "remote error: {}", 0)]
+    RemoteError(String),
+
+    #[fail(
+        display = "database schema version {} is newer than this pix supports, upgrade pix or use another --db_path",
+        0
+    )]
+    SchemaTooNew(u32),
+
+    #[fail(display = "unsupported archive: {}", 0)]
+    UnsupportedArchive(String),
+
+    #[fail(display = "raw error: {}", 0)]
+    RawError(String),
+
+    #[fail(display = "svg error: {}", 0)]
+    SvgError(String),
+
+    #[fail(display = "external decoder error: {}", 0)]
+    ExternalDecoderError(String),
+
+    #[fail(display = "cancelled")]
+    Cancelled,
+
+    #[fail(display = "thumbnailer panicked: {}", 0)]
+    Panicked(String),
 }
 
 type R<T> = std::result::Result<T, E>;
@@ -92,6 +146,16 @@ impl TileRef {
         Self((chunk as u64) | ((index % (1u64 << 40)) << 16) | ((size.0 as u64) << 56))
     }
 
+    // The per-image index shared by all tiles of an image.
+    fn index(&self) -> u64 {
+        (self.0 & 0x00FF_FFFF_FFFF_0000u64) >> 16
+    }
+
+    // The same tile of the image with another index.
+    fn with_index(&self, index: u64) -> Self {
+        Self((self.0 & !0x00FF_FFFF_FFFF_0000u64) | ((index % (1u64 << 40)) << 16))
+    }
+
     #[cfg(test)]
     fn deconstruct(&self) -> (Pow2, u64, u16) {
         let size = ((self.0 & 0xFF00_0000_0000_0000u64) >> 56) as u8;
@@ -132,21 +196,110 @@ fn tile_ref_test() {
     assert_eq!(
         TileRef::new(Pow2(0u8), 0u64, 0xFFFFu16).deconstruct(),
         (Pow2(0u8), 0u64, 0xFFFFu16)
-    )
+    );
+
+    assert_eq!(
+        TileRef::new(Pow2(0xFFu8), 0x00AB_CDEF_0123u64, 0xFFFFu16).index(),
+        0x00AB_CDEF_0123u64
+    );
+}
+
+// Encoding of a thumbnail's tiles, see `codec`. Only append variants, they're persisted by index.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+enum TileFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Raw,
+    RawZstd,
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 struct Thumb {
     img_size: [u32; 2],
     tile_refs: Vec<TileRef>,
+    format: TileFormat,
+
+    // Has transparent pixels.
+    alpha: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Metadata {
     thumbs: Vec<Thumb>,
+
+    // Whether the original's embedded ICC profile was converted to sRGB.
+    icc_converted: bool,
+
+    animation: Option<Animation>,
+
+    // How long the original plays, for videos from an external decoder.
+    duration_ms: Option<u64>,
+}
+
+// The frames of an animated image. The first frame is the image's pyramid.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+struct Animation {
+    // How long each frame shows, starting with the first.
+    delays_ms: Vec<u32>,
+
+    // The frames after the first, all at one size.
+    frames: Vec<Thumb>,
+}
+
+impl Animation {
+    // The frame showing `time_ms` into playing the animation in a loop.
+    fn frame_at(&self, time_ms: u64) -> usize {
+        let total: u64 = self.delays_ms.iter().map(|&d| u64::from(d)).sum();
+        if total == 0 {
+            return 0;
+        }
+
+        let mut t = time_ms % total;
+        for (i, &delay) in self.delays_ms.iter().enumerate() {
+            if t < u64::from(delay) {
+                return i;
+            }
+            t -= u64::from(delay);
+        }
+        0
+    }
+}
+
+#[test]
+fn animation_frame_at() {
+    let animation = Animation {
+        delays_ms: vec![100, 50, 100],
+        frames: Vec::new(),
+    };
+    assert_eq!(animation.frame_at(0), 0);
+    assert_eq!(animation.frame_at(99), 0);
+    assert_eq!(animation.frame_at(100), 1);
+    assert_eq!(animation.frame_at(150), 2);
+    assert_eq!(animation.frame_at(250), 0);
 }
 
 impl Metadata {
+    // The pyramid and animation frames.
+    fn all_thumbs(&self) -> impl Iterator<Item = &Thumb> {
+        let frames = self.animation.iter().flat_map(|a| a.frames.iter());
+        self.thumbs.iter().chain(frames)
+    }
+
+    fn all_thumbs_mut(&mut self) -> impl Iterator<Item = &mut Thumb> {
+        let frames = self.animation.iter_mut().flat_map(|a| a.frames.iter_mut());
+        self.thumbs.iter_mut().chain(frames)
+    }
+
+    fn tile_refs(&self) -> impl Iterator<Item = &TileRef> {
+        self.all_thumbs().flat_map(|thumb| thumb.tile_refs.iter())
+    }
+
+    // The tile index shared by all of this image's tiles.
+    fn index(&self) -> Option<u64> {
+        self.tile_refs().next().map(TileRef::index)
+    }
+
     fn nearest(&self, target_size: u32) -> usize {
         let mut found = None;
 
@@ -244,6 +397,15 @@ impl Thumb {
             vec2_scale(gaps, 0.5)
         };
 
+        if self.alpha {
+            let [w, h] = vec2_f64(self.img_size);
+            let rect = [x_offset, y_offset, w, h];
+            // Checkerboard squares are a constant size on screen.
+            let square = 16.0 * max_dimension / view.zoom;
+            view.alpha_background
+                .draw(rect, square, draw_state, trans, g);
+        }
+
         let tile_spec = self.tile_spec();
 
         let mut it = self.tile_refs.iter();
@@ -293,6 +455,9 @@ struct App {
     focus: Option<Vector2<f64>>,
 
     shift_held: bool,
+
+    // How long drawing the last frame took.
+    render_time: std::time::Duration,
 }
 
 pub struct Stopwatch {
@@ -311,6 +476,21 @@ impl Stopwatch {
     pub fn done(&self) -> bool {
         self.start.elapsed() >= self.duration
     }
+
+    pub fn elapsed(&self) -> std::time::Duration {
+        self.start.elapsed()
+    }
+
+    /// Time taken so far apart from a loader that started `loader_start` in and ran for `loaded`.
+    /// It may use the rest of the budget, only what it took past the budget counts.
+    pub fn elapsed_besides(
+        &self,
+        loader_start: std::time::Duration,
+        loaded: std::time::Duration,
+    ) -> std::time::Duration {
+        let allowed = self.duration.saturating_sub(loader_start);
+        self.elapsed() - loaded + loaded.saturating_sub(allowed)
+    }
 }
 
 impl App {
@@ -318,8 +498,10 @@ impl App {
         images: Vec<image::Image>,
         db: Arc<database::Database>,
         thumbnailer: Thumbnailer,
+        alpha_background: view::Background,
     ) -> Self {
-        let view = view::View::new(images.len());
+        let mut view = view::View::new(images.len());
+        view.alpha_background = alpha_background;
 
         let groups = Groups::from(images, vec2_u32(view.grid_size));
 
@@ -349,6 +531,7 @@ impl App {
             cursor_captured: false,
 
             shift_held: false,
+            render_time: std::time::Duration::default(),
 
             focus: None,
         }
@@ -368,7 +551,7 @@ impl App {
 
     fn update(&mut self, args: UpdateArgs) {
         let _s = ScopedDuration::new("App::update");
-        let stopwatch = Stopwatch::from_millis(10);
+        let stopwatch = Stopwatch::from_millis(thumbnailer::FRAME_BUDGET_MS);
 
         let grid_size = vec2_u32(self.view.grid_size);
         if grid_size != self.groups.grid_size() {
@@ -381,22 +564,32 @@ impl App {
 
         if self.focus.is_none() {
             self.groups.recheck(&self.view);
+            self.thumbnailer.clear_queue();
             self.focus = Some(self.view.mouse_dist([0, 0]));
         }
 
         self.recv_thumbs();
 
-        self.groups.make_thumbs(&mut self.thumbnailer);
+        self.groups.make_thumbs(&self.view, &mut self.thumbnailer);
 
+        let loader_start = stopwatch.elapsed();
         self.groups
             .load_cache(&self.view, &*self.db, &mut self.texture_context, &stopwatch);
+        let loaded = stopwatch.elapsed() - loader_start;
+
+        // Drawing the last frame counts too.
+        let frame = stopwatch.elapsed_besides(loader_start, loaded) + self.render_time;
+        self.thumbnailer.adapt(frame);
     }
 
     pub fn recv_thumbs(&mut self) {
         let _s = ScopedDuration::new("App::recv_thumbs");
 
-        for (i, metadata_res) in self.thumbnailer.recv() {
-            self.groups.update_metadata(i, metadata_res);
+        for done in self.thumbnailer.recv() {
+            if let Err(e) = &done.res {
+                error!("make_thumb: {}: {} ({:?})", done.file.path, e, done.timing);
+            }
+            self.groups.update_metadata(done.i, done.res);
         }
     }
 
@@ -478,12 +671,26 @@ impl App {
         self.force_refocus();
     }
 
+    // Forgets which images couldn't be thumbnailed and tries them again.
+    fn retry_errored(&mut self) {
+        match self.db.clear_failures() {
+            Ok(n) => info!("cleared {} failure records", n),
+            Err(e) => error!("clear_failures: {:?}", e),
+        }
+        info!("retrying {} images", self.groups.retry_errored());
+        self.force_refocus();
+    }
+
     fn button(&mut self, b: ButtonArgs) {
         match (b.state, b.button) {
             (ButtonState::Press, Button::Keyboard(Key::Z)) => {
                 self.reset();
             }
 
+            (ButtonState::Press, Button::Keyboard(Key::R)) => {
+                self.retry_errored();
+            }
+
             (ButtonState::Press, Button::Keyboard(Key::F)) => {
                 let mut settings = self.window_settings.clone();
                 settings.set_fullscreen(!settings.get_fullscreen());
@@ -589,12 +796,28 @@ impl App {
 
                 e.button(|b| self.button(b));
 
+                // The event loop would sleep until the next frame, wait for jobs instead so their
+                // thumbnails are taken as soon as they're done.
+                e.idle(|args| {
+                    if self.thumbnailer.is_busy() {
+                        self.thumbnailer
+                            .wait(std::time::Duration::from_secs_f64(args.dt.max(0.0)));
+                    }
+                });
+
+                if self.thumbnailer.has_completions() {
+                    self.recv_thumbs();
+                }
+
                 // borrowck
                 let v = &self.view;
                 let groups = &self.groups;
+                let render_time = &mut self.render_time;
                 self.window.draw_2d(&e, |c, g, _device| {
                     let _s = ScopedDuration::new("draw_2d");
+                    let start = std::time::Instant::now();
                     Self::draw_2d(&e, c, g, v, groups);
+                    *render_time = start.elapsed();
                 });
             } else {
                 break;
@@ -610,6 +833,23 @@ pub struct File {
     file_size: u64,
 }
 
+impl File {
+    fn from_metadata(path: String, metadata: &std::fs::Metadata) -> Self {
+        let modified: u64 = metadata
+            .modified()
+            .expect("metadata modified")
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("duration since unix epoch")
+            .as_secs();
+
+        Self {
+            path,
+            modified,
+            file_size: metadata.len(),
+        }
+    }
+}
+
 fn find_images(dirs: Vec<String>) -> Vec<Arc<File>> {
     let _s = ScopedDuration::new("find_images");
 
@@ -643,15 +883,6 @@ fn find_images(dirs: Vec<String>) -> Vec<Arc<File>> {
                 continue;
             }
 
-            let file_size = metadata.len();
-
-            let modified: u64 = metadata
-                .modified()
-                .expect("metadata modified")
-                .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                .expect("duration since unix epoch")
-                .as_secs();
-
             let path = entry.path();
 
             let path = match path.canonicalize() {
@@ -669,13 +900,7 @@ fn find_images(dirs: Vec<String>) -> Vec<Arc<File>> {
                 continue;
             };
 
-            let file = File {
-                path,
-                modified,
-                file_size,
-            };
-
-            ret.push(Arc::new(file));
+            ret.push(Arc::new(File::from_metadata(path, &metadata)));
         }
     }
 
@@ -683,6 +908,44 @@ fn find_images(dirs: Vec<String>) -> Vec<Arc<File>> {
     ret
 }
 
+fn archive_arg() -> Arg<'static, 'static> {
+    Arg::with_name("archive")
+        .value_name("ARCHIVE")
+        .required(true)
+        .help("Archive path.")
+}
+
+fn rewrite_prefix_arg() -> Arg<'static, 'static> {
+    Arg::with_name("rewrite_prefix")
+        .long("--rewrite_prefix")
+        .value_name("OLD=NEW")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Replace the OLD prefix of image paths with NEW.")
+}
+
+fn open_database(backend: store::Backend, path: &str) -> database::Database {
+    match database::Database::open(backend, path) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Unable to open {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Writes back what's only in memory. The database isn't dropped while it's being served to other
+// instances so this can't be left to its destructor.
+fn close_database(db: &database::Database) {
+    if let Err(e) = db.flush_access_times() {
+        error!("flush access times: {:?}", e);
+    }
+    if let Err(e) = db.flush() {
+        error!("flush database: {:?}", e);
+    }
+}
+
 fn main() {
     env_logger::init();
 
@@ -705,18 +968,191 @@ fn main() {
                 .value_name("COUNT")
                 .takes_value(true)
                 .required(false)
-                .help("Set number of background thumbnailer threads."),
+                .global(true)
+                .help("Set number of background thumbnailer threads decoding images."),
+        )
+        .arg(
+            Arg::with_name("resize_threads")
+                .long("--resize_threads")
+                .value_name("COUNT")
+                .takes_value(true)
+                .global(true)
+                .help("Set number of thumbnailer threads resizing images (default half of --threads)."),
+        )
+        .arg(
+            Arg::with_name("encode_threads")
+                .long("--encode_threads")
+                .value_name("COUNT")
+                .takes_value(true)
+                .global(true)
+                .help("Set number of thumbnailer threads encoding tiles (default half of --threads)."),
+        )
+        .arg(
+            Arg::with_name("adaptive_threads")
+                .long("--adaptive_threads")
+                .global(true)
+                .help("Run fewer thumbnailer jobs while frames take too long."),
         )
         .arg(
             Arg::with_name("db_path")
                 .long("--db_path")
                 .value_name("PATH")
                 .takes_value(true)
+                .global(true)
                 .help("Alternate thumbnail database path."),
         )
+        .arg(
+            Arg::with_name("db_backend")
+                .long("--db_backend")
+                .value_name("BACKEND")
+                .takes_value(true)
+                .global(true)
+                .possible_values(store::Backend::NAMES)
+                .default_value("sled")
+                .help("Thumbnail database storage backend."),
+        )
+        .arg(
+            Arg::with_name("gc_interval_days")
+                .long("--gc_interval_days")
+                .value_name("DAYS")
+                .takes_value(true)
+                .default_value("7")
+                .help("Garbage collect the database in the background at most this often (0 to disable)."),
+        )
+        .arg(
+            Arg::with_name("cache_max_bytes")
+                .long("--cache_max_bytes")
+                .value_name("BYTES")
+                .takes_value(true)
+                .global(true)
+                .help("Evict the least recently viewed thumbnails once the database takes up more than this on disk."),
+        )
+        .arg(
+            Arg::with_name("thumb_mem_limit")
+                .long("--thumb_mem_limit")
+                .value_name("BYTES")
+                .takes_value(true)
+                .global(true)
+                .help("Queue thumbnailing jobs rather than let them use more memory than this together."),
+        )
+        .arg(
+            Arg::with_name("alpha_background")
+                .long("--alpha_background")
+                .value_name("COLOR")
+                .takes_value(true)
+                .default_value("checkerboard")
+                .help("Draw transparent images over \"checkerboard\" or an RRGGBB color."),
+        )
+        .arg(
+            Arg::with_name("content_keys")
+                .long("--content_keys")
+                .global(true)
+                .help("Key new thumbnails by file content so moved and copied images reuse them."),
+        )
+        .arg(
+            Arg::with_name("tile_codec")
+                .long("--tile_codec")
+                .value_name("CODEC")
+                .takes_value(true)
+                .global(true)
+                .possible_values(codec::Codec::NAMES)
+                .default_value("jpeg")
+                .help("Encoding of new thumbnail tiles. Transparent images get PNG instead of JPEG."),
+        )
+        .arg(
+            Arg::with_name("tile_quality")
+                .long("--tile_quality")
+                .value_name("Q,...")
+                .takes_value(true)
+                .global(true)
+                .default_value("100,70")
+                .help("Lossy tile quality (1-100) from the largest thumbnail down, the last applies to the rest."),
+        )
+        .arg(
+            Arg::with_name("resample_filter")
+                .long("--resample_filter")
+                .value_name("FILTER,...")
+                .takes_value(true)
+                .global(true)
+                .default_value("lanczos3")
+                .help("Filter (lanczos3, catmullrom, box or nearest) thumbnails are downsampled with from the largest down, the last applies to the rest."),
+        )
+        .arg(
+            Arg::with_name("linear_resample")
+                .long("--linear_resample")
+                .global(true)
+                .help("Downsample thumbnails in linear light."),
+        )
+        .arg(
+            Arg::with_name("external_decoder")
+                .long("--external_decoder")
+                .value_name("EXT,...=COMMAND")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Thumbnail files with these extensions from the image COMMAND writes to {output}, given {input}."),
+        )
+        .arg(
+            Arg::with_name("xdg_thumbnails")
+                .long("--xdg_thumbnails")
+                .value_name("MODE")
+                .takes_value(true)
+                .global(true)
+                .possible_values(xdg::Mode::NAMES)
+                .default_value("read")
+                .help("Show the shared ~/.cache/thumbnails while images are decoded and for ones that can't be, and publish to it with \"write\"."),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Remove stale metadata and orphaned tiles from the thumbnail database."),
+        )
+        .subcommand(
+            SubCommand::with_name("thumbnail")
+                .about("Thumbnail images without opening a window, to pre-warm the cache.")
+                .arg(
+                    Arg::with_name("paths")
+                        .value_name("PATHS")
+                        .multiple(true)
+                        .help("Images or directories of images to thumbnail."),
+                )
+                .arg(
+                    Arg::with_name("retry_errored")
+                        .long("--retry_errored")
+                        .help("Try again images that couldn't be thumbnailed before."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Move thumbnails between databases.")
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Write every up to date thumbnail to an archive.")
+                        .arg(archive_arg())
+                        .arg(rewrite_prefix_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Add the thumbnails in an archive to the database.")
+                        .arg(archive_arg())
+                        .arg(rewrite_prefix_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check that every thumbnail in the database is readable.")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("--repair")
+                        .help("Remove broken entries so their images are thumbnailed again."),
+                ),
+        )
         .get_matches();
 
-    let paths = matches
+    let headless = matches.subcommand_matches("thumbnail");
+
+    let paths = headless
+        .unwrap_or(&matches)
         .values_of_lossy("paths")
         .unwrap_or_else(|| vec![String::from(".")]);
     info!("Paths: {:?}", paths);
@@ -726,17 +1162,128 @@ fn main() {
     } else {
         num_cpus::get()
     };
-    info!("Thumbnailer threads {}", thumbnailer_threads);
+    let thumbnailer_threads = {
+        let mut threads = thumbnailer::Threads::new(thumbnailer_threads);
+        if let Some(resize) = matches.value_of("resize_threads") {
+            threads.resize = resize.parse().expect("not an int");
+        }
+        if let Some(encode) = matches.value_of("encode_threads") {
+            threads.encode = encode.parse().expect("not an int");
+        }
+        threads.jobs = threads.decode + threads.resize + threads.encode;
+        threads.adaptive = matches.is_present("adaptive_threads");
+        threads
+    };
+    info!("Thumbnailer threads {:?}", thumbnailer_threads);
+
+    let db_backend =
+        store::Backend::from_name(matches.value_of("db_backend").unwrap()).expect("db backend");
 
     let db_path: String = if let Some(db_path) = matches.value_of("db_path") {
         db_path.to_owned()
     } else {
         let mut db_path = dirs::cache_dir().expect("cache dir");
-        db_path.push("pix/thumbs.db");
+        db_path.push(db_backend.default_path());
         db_path.to_str().expect("db path as str").to_owned()
     };
     info!("Database path: {}", db_path);
 
+    let gc_interval: u64 = matches
+        .value_of("gc_interval_days")
+        .unwrap()
+        .parse::<u64>()
+        .expect("not an int")
+        * 24
+        * 60
+        * 60;
+
+    let alpha_background = view::Background::parse(matches.value_of("alpha_background").unwrap())
+        .expect("--alpha_background takes checkerboard or RRGGBB");
+
+    let cache_max_bytes: Option<u64> = matches
+        .value_of("cache_max_bytes")
+        .map(|v| v.parse().expect("not an int"));
+
+    if matches.subcommand_matches("gc").is_some() {
+        let db = open_database(db_backend, &db_path);
+        let stats = db.gc().expect("gc");
+        println!(
+            "Removed {} of {} metadata entries and {} of {} tiles, freeing {} bytes.",
+            stats.metadata_removed,
+            stats.metadata_removed + stats.metadata_kept,
+            stats.tiles_removed,
+            stats.tiles_removed + stats.tiles_kept,
+            stats.bytes_freed,
+        );
+        if let Some(max_bytes) = cache_max_bytes {
+            let stats = db.evict(max_bytes).expect("evict");
+            println!(
+                "Evicted {} images, freeing {} bytes. Cache size is now {} bytes.",
+                stats.images_evicted, stats.bytes_freed, stats.bytes_remaining,
+            );
+        }
+        return;
+    }
+
+    if let Some(cache) = matches.subcommand_matches("cache") {
+        let (export, matches) = match cache.subcommand() {
+            ("export", Some(matches)) => (true, matches),
+            ("import", Some(matches)) => (false, matches),
+            _ => {
+                eprintln!("{}", cache.usage());
+                std::process::exit(1);
+            }
+        };
+
+        let archive = std::path::Path::new(matches.value_of("archive").unwrap());
+        let rewrites =
+            archive::Rewrites::parse(matches.values_of("rewrite_prefix").into_iter().flatten())
+                .expect("--rewrite_prefix takes OLD=NEW");
+
+        let db = open_database(db_backend, &db_path);
+        let stats = if export {
+            archive::export(&db, archive, &rewrites)
+        } else {
+            archive::import(&db, archive, &rewrites)
+        };
+        let stats = match stats {
+            Ok(stats) => stats,
+            Err(e) => {
+                eprintln!("{}: {}", archive.display(), e);
+                std::process::exit(1);
+            }
+        };
+        println!(
+            "{} {} images ({} tiles, {} bytes), skipped {}.",
+            if export { "Exported" } else { "Imported" },
+            stats.images,
+            stats.tiles,
+            stats.bytes,
+            stats.skipped,
+        );
+        return;
+    }
+
+    if let Some(fsck) = matches.subcommand_matches("fsck") {
+        let repair = fsck.is_present("repair");
+        let db = open_database(db_backend, &db_path);
+        let stats = db.fsck(repair).expect("fsck");
+        for problem in &stats.problems {
+            println!("{}", problem);
+        }
+        println!(
+            "Checked {} metadata entries and {} tiles, {} broken, {} removed.",
+            stats.metadata_checked,
+            stats.tiles_checked,
+            stats.problems.len(),
+            stats.metadata_removed,
+        );
+        if !stats.problems.is_empty() && !repair {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     /////////
     // RUN //
     /////////
@@ -749,7 +1296,93 @@ fn main() {
         info!("Found {} files", files.len());
     }
 
-    let db = Arc::new(database::Database::open(&db_path).expect("db open"));
+    let db = {
+        let mut db = open_database(db_backend, &db_path);
+        db.set_content_keys(matches.is_present("content_keys"));
+        db.set_max_bytes(cache_max_bytes);
+        Arc::new(db)
+    };
+
+    // Let other instances share the database while this one is running.
+    let _server = if db.is_remote() || db_backend == store::Backend::Memory {
+        None
+    } else {
+        remote::Server::start(Arc::clone(&db), &remote::socket_path(&db_path))
+            .map_err(|e| error!("unable to serve database: {:?}", e))
+            .ok()
+    };
+
+    let thumbnailer_options = thumbnailer::Options {
+        xdg: xdg::Mode::from_name(matches.value_of("xdg_thumbnails").unwrap()).expect("xdg mode"),
+        codec: codec::Codec::from_name(matches.value_of("tile_codec").unwrap()).expect("codec"),
+        quality: matches
+            .value_of("tile_quality")
+            .unwrap()
+            .split(',')
+            .map(|q| match q.parse::<u8>() {
+                Ok(q) if (1..=100).contains(&q) => q,
+                _ => panic!("--tile_quality takes numbers from 1 to 100"),
+            })
+            .collect(),
+        external_decoders: {
+            // Configured decoders come first so they can override the built in one.
+            let mut decoders: Vec<external::Decoder> = matches
+                .values_of("external_decoder")
+                .into_iter()
+                .flatten()
+                .map(|spec| match external::Decoder::parse(spec) {
+                    Some(decoder) => decoder,
+                    None => panic!(
+                        "--external_decoder takes EXT,...=COMMAND with {} in COMMAND",
+                        "{output}"
+                    ),
+                })
+                .collect();
+            decoders.push(external::Decoder::video());
+            decoders
+        },
+        mem_limit: matches
+            .value_of("thumb_mem_limit")
+            .map(|v| v.parse().expect("not an int")),
+        resampling: resample::Resampling {
+            filters: resample::Filter::parse_list(matches.value_of("resample_filter").unwrap())
+                .unwrap_or_else(|| {
+                    panic!(
+                        "--resample_filter takes a list of {}",
+                        resample::Filter::NAMES.join(", ")
+                    )
+                }),
+            linear: matches.is_present("linear_resample"),
+        },
+        // Nothing shows them without a window.
+        previews: headless.is_none(),
+    };
+
+    let thumbnailer = Thumbnailer::new(Arc::clone(&db), thumbnailer_threads, thumbnailer_options);
+
+    if let Some(thumbnail) = headless {
+        if thumbnail.is_present("retry_errored") {
+            match db.clear_failures() {
+                Ok(n) => info!("cleared {} failure records", n),
+                Err(e) => error!("clear_failures: {:?}", e),
+            }
+        }
+        // Before thumbnailing so entries an upgrade left to check are made again now.
+        if let Err(e) = db.recheck() {
+            error!("recheck: {:?}", e);
+        }
+        let summary = headless::run(&db, files, thumbnailer);
+        if let Some(max_bytes) = cache_max_bytes {
+            if let Err(e) = db.evict(max_bytes) {
+                error!("evict: {:?}", e);
+            }
+        }
+        close_database(&db);
+        if !summary.failed.is_empty() {
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let images: Vec<image::Image> = {
         let _s = ScopedDuration::new("main::load_metadata");
@@ -759,7 +1392,13 @@ fn main() {
             .map(|(i, file)| {
                 let metadata = match db.get_metadata(&file) {
                     Ok(Some(metadata)) => MetadataState::Some(metadata),
-                    Ok(None) => MetadataState::Missing,
+                    Ok(None) => match db.get_failure(&file) {
+                        Ok(Some(failure)) => {
+                            info!("skipping {}: {:?}", file.path, failure);
+                            MetadataState::Errored
+                        }
+                        _ => MetadataState::Missing,
+                    },
                     Err(e) => {
                         error!("error loading metadata for: {:?}: {:?}", file, e);
                         MetadataState::Errored
@@ -770,14 +1409,37 @@ fn main() {
             .collect()
     };
 
-    let uid_base = db.reserve(images.len());
-
-    let thumbnailer = Thumbnailer::new(Arc::clone(&db), uid_base, thumbnailer_threads);
+    // Every image loaded above has been touched this session so eviction takes them last.
+    let gc_due = gc_interval > 0 && db.gc_due(gc_interval).unwrap_or(false);
+    {
+        let db = Arc::clone(&db);
+        std::thread::Builder::new()
+            .name("gc".to_owned())
+            .spawn(move || {
+                // Entries an upgrade left to check are made again from the next launch.
+                if let Err(e) = db.recheck() {
+                    error!("background recheck: {:?}", e);
+                }
+                if gc_due {
+                    if let Err(e) = db.gc() {
+                        error!("background gc: {:?}", e);
+                    }
+                }
+                if let Some(max_bytes) = cache_max_bytes {
+                    if let Err(e) = db.evict(max_bytes) {
+                        error!("background evict: {:?}", e);
+                    }
+                }
+            })
+            .expect("spawn gc thread");
+    }
 
     {
         let _s = ScopedDuration::new("uptime");
-        App::new(images, Arc::clone(&db), thumbnailer).run();
+        App::new(images, Arc::clone(&db), thumbnailer, alpha_background).run();
     }
 
+    close_database(&db);
+
     stats::dump();
 }
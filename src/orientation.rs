@@ -0,0 +1,125 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// EXIF orientation. Cameras store pixels in sensor order and record how to turn them for display.
+
+use ::image::DynamicImage;
+use std::io::BufReader;
+
+/// The EXIF `Orientation` tag, 1 (as stored) to 8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orientation(u32);
+
+impl Default for Orientation {
+    fn default() -> Self {
+        Orientation(1)
+    }
+}
+
+impl Orientation {
+    /// Reads the orientation of the image at `path`. Images without (valid) EXIF data are
+    /// displayed as stored.
+    pub fn read(path: &str) -> Self {
+        let _s = crate::stats::ScopedDuration::new("Orientation::read");
+
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Self::default(),
+        };
+
+        let exif = match exif::Reader::new().read_from_container(&mut BufReader::new(file)) {
+            Ok(exif) => exif,
+            Err(_) => return Self::default(),
+        };
+
+        exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .filter(|v| (1..=8).contains(v))
+            .map(Orientation)
+            .unwrap_or_default()
+    }
+
+    pub fn is_identity(self) -> bool {
+        self.0 == 1
+    }
+
+    /// Turns `image` from stored to display orientation.
+    pub fn apply(self, image: DynamicImage) -> DynamicImage {
+        match self.0 {
+            2 => image.fliph(),
+            3 => image.rotate180(),
+            4 => image.flipv(),
+            5 => image.rotate90().fliph(),
+            6 => image.rotate90(),
+            7 => image.rotate270().fliph(),
+            8 => image.rotate270(),
+            _ => image,
+        }
+    }
+}
+
+// A JPEG with nothing but an EXIF segment saying it's rotated by 90 degrees.
+#[cfg(test)]
+pub fn rotated_jpeg() -> Vec<u8> {
+    let tiff: &[u8] = &[
+        b'M', b'M', 0, 42, 0, 0, 0, 8, // header, IFD at 8
+        0, 1, // one entry
+        0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, // Orientation, 1 SHORT, 6
+        0, 0, 0, 0, // no next IFD
+    ];
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+    jpeg.extend_from_slice(&(2 + 6 + tiff.len() as u16).to_be_bytes());
+    jpeg.extend_from_slice(b"Exif\0\0");
+    jpeg.extend_from_slice(tiff);
+    jpeg.extend_from_slice(&[0xff, 0xd9]);
+    jpeg
+}
+
+#[test]
+fn apply() {
+    use ::image::{GenericImageView, Rgba};
+
+    // Marks the stored top left corner of a 3x2 image.
+    let mut image = DynamicImage::new_rgba8(3, 2);
+    image
+        .as_mut_rgba8()
+        .unwrap()
+        .put_pixel(0, 0, Rgba([255; 4]));
+
+    // Where the marked pixel ends up for display.
+    let expected = [
+        (1, (3, 2), (0, 0)),
+        (2, (3, 2), (2, 0)),
+        (3, (3, 2), (2, 1)),
+        (4, (3, 2), (0, 1)),
+        (5, (2, 3), (0, 0)),
+        (6, (2, 3), (1, 0)),
+        (7, (2, 3), (1, 2)),
+        (8, (2, 3), (0, 2)),
+    ];
+
+    for &(v, dimensions, (x, y)) in &expected {
+        let turned = Orientation(v).apply(image.clone());
+        assert_eq!(turned.dimensions(), dimensions, "orientation {}", v);
+        assert_eq!(turned.get_pixel(x, y), Rgba([255; 4]), "orientation {}", v);
+    }
+
+    assert_eq!(Orientation::read("/nonexistent"), Orientation::default());
+
+    let dir = crate::store::test_path("orientation");
+    let path = dir.join("rotated.jpg");
+    std::fs::write(&path, rotated_jpeg()).unwrap();
+    assert_eq!(Orientation::read(path.to_str().unwrap()), Orientation(6));
+    let _ = std::fs::remove_dir_all(&dir);
+}
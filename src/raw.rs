@@ -0,0 +1,377 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Camera RAW files. Most are TIFF based and embed a JPEG the camera rendered, which is faster and
+// better looking than anything made from the sensor data here, so that is preferred. Otherwise the
+// sensor data is decoded by rawloader and demosaiced at half resolution, which is plenty for
+// thumbnails.
+
+use crate::{E, R};
+use ::image::{DynamicImage, GenericImageView, RgbImage};
+use std::collections::BTreeSet;
+
+static EXTENSIONS: &[&str] = &[
+    "3fr", "arw", "cr2", "dcr", "dng", "erf", "kdc", "mef", "mos", "mrw", "nef", "nrw", "orf",
+    "pef", "raf", "rw2", "sr2", "srf", "srw",
+];
+
+// Previews smaller than this (on their longer side) are camera thumbnails, not renderings of the
+// whole image.
+static MIN_PREVIEW_SIZE: u32 = 1024;
+
+// Stop walking IFDs in files with loops or absurdly many.
+static MAX_IFDS: usize = 64;
+
+static TAG_COMPRESSION: u16 = 0x103;
+static TAG_STRIP_OFFSETS: u16 = 0x111;
+static TAG_STRIP_BYTE_COUNTS: u16 = 0x117;
+static TAG_SUB_IFDS: u16 = 0x14a;
+static TAG_JPEG_OFFSET: u16 = 0x201;
+static TAG_JPEG_LENGTH: u16 = 0x202;
+
+// sRGB (D65) to XYZ.
+static XYZ_FROM_SRGB: [[f32; 3]; 3] = [
+    [0.412_453, 0.357_580, 0.180_423],
+    [0.212_671, 0.715_160, 0.072_169],
+    [0.019_334, 0.119_193, 0.950_227],
+];
+
+/// Whether `path` has the extension of a RAW format.
+pub fn is_raw(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Opens the RAW image at `path`.
+pub fn open(path: &str) -> R<DynamicImage> {
+    let data = std::fs::read(path).map_err(E::IoError)?;
+
+    if let Some(image) = preview(&data) {
+        let (w, h) = image.dimensions();
+        info!("{}: using the embedded {}x{} preview", path, w, h);
+        return Ok(image);
+    }
+
+    info!("{}: no usable preview, demosaicing", path);
+    demosaic(&data)
+}
+
+// TIFF structure, just enough to find embedded JPEGs.
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let b = self.data.get(offset..offset + 2)?;
+        let b = [b[0], b[1]];
+        Some(if self.little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let b = self.data.get(offset..offset + 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Some(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    // The SHORT, LONG or IFD values of the entry at `entry`.
+    fn values(&self, entry: usize) -> Option<Vec<u32>> {
+        let kind = self.u16(entry + 2)?;
+        let count = self.u32(entry + 4)? as usize;
+        let size = match kind {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return None,
+        };
+
+        // Values that fit are stored in the entry itself.
+        let start = if size * count <= 4 {
+            entry + 8
+        } else {
+            self.u32(entry + 8)? as usize
+        };
+
+        (0..count.min(self.data.len() / size))
+            .map(|i| match size {
+                2 => self.u16(start + 2 * i).map(u32::from),
+                _ => self.u32(start + 4 * i),
+            })
+            .collect()
+    }
+
+    // Offsets and lengths of every JPEG in any IFD.
+    fn jpegs(&self) -> Vec<(usize, usize)> {
+        let mut ret = Vec::new();
+
+        let mut todo: Vec<usize> = self.u32(4).map(|o| o as usize).into_iter().collect();
+        let mut seen = BTreeSet::new();
+
+        while let Some(ifd) = todo.pop() {
+            if ifd == 0 || !seen.insert(ifd) || seen.len() > MAX_IFDS {
+                continue;
+            }
+
+            let count = match self.u16(ifd) {
+                Some(count) => count as usize,
+                None => continue,
+            };
+
+            let mut tags: Vec<(u16, Vec<u32>)> = Vec::new();
+            for i in 0..count {
+                let entry = ifd + 2 + 12 * i;
+                if let (Some(tag), Some(values)) = (self.u16(entry), self.values(entry)) {
+                    tags.push((tag, values));
+                }
+            }
+            let get = |tag: u16| {
+                tags.iter()
+                    .find(|(t, _)| *t == tag)
+                    .map(|(_, values)| values.as_slice())
+            };
+
+            if let (Some(&[offset]), Some(&[len])) = (get(TAG_JPEG_OFFSET), get(TAG_JPEG_LENGTH)) {
+                ret.push((offset as usize, len as usize));
+            }
+
+            // Old and new style JPEG compressed strips, which are also used for lossless raw data.
+            if let (Some(&[6]) | Some(&[7]), Some(&[offset]), Some(&[len])) = (
+                get(TAG_COMPRESSION),
+                get(TAG_STRIP_OFFSETS),
+                get(TAG_STRIP_BYTE_COUNTS),
+            ) {
+                ret.push((offset as usize, len as usize));
+            }
+
+            if let Some(sub_ifds) = get(TAG_SUB_IFDS) {
+                todo.extend(sub_ifds.iter().map(|&o| o as usize));
+            }
+
+            if let Some(next) = self.u32(ifd + 2 + 12 * count) {
+                todo.push(next as usize);
+            }
+        }
+
+        ret
+    }
+}
+
+// The largest embedded JPEG that decodes and is big enough.
+fn preview(data: &[u8]) -> Option<DynamicImage> {
+    let _s = crate::stats::ScopedDuration::new("raw::preview");
+
+    let tiff = Tiff::new(data)?;
+
+    let mut jpegs = tiff.jpegs();
+    jpegs.sort_by_key(|&(_, len)| std::cmp::Reverse(len));
+
+    for (offset, len) in jpegs {
+        let jpeg = match data.get(offset..offset.saturating_add(len)) {
+            Some(jpeg) if jpeg.starts_with(&[0xff, 0xd8]) => jpeg,
+            _ => continue,
+        };
+
+        // Lossless JPEG (raw data) doesn't decode.
+        if let Ok(image) = ::image::load_from_memory_with_format(jpeg, ::image::ImageFormat::JPEG) {
+            let (w, h) = image.dimensions();
+            if std::cmp::max(w, h) >= MIN_PREVIEW_SIZE {
+                return Some(image);
+            }
+        }
+    }
+
+    None
+}
+
+fn invert(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let mut ret = [[0.0; 3]; 3];
+    for (i, row) in ret.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            // Transposed cofactors.
+            let (r0, r1) = ((j + 1) % 3, (j + 2) % 3);
+            let (c0, c1) = ((i + 1) % 3, (i + 2) % 3);
+            *v = (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) / det;
+        }
+    }
+    Some(ret)
+}
+
+// Camera to sRGB, as dcraw does it: rows of sRGB to camera are normalized so white stays white.
+fn srgb_from_camera(xyz_to_cam: &[[f32; 3]; 4]) -> Option<[[f32; 3]; 3]> {
+    let mut cam_from_srgb = [[0.0; 3]; 3];
+    for (i, row) in cam_from_srgb.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| xyz_to_cam[i][k] * XYZ_FROM_SRGB[k][j]).sum();
+        }
+        let sum: f32 = row.iter().sum();
+        if sum.abs() < f32::EPSILON {
+            return None;
+        }
+        for v in row.iter_mut() {
+            *v /= sum;
+        }
+    }
+    invert(cam_from_srgb)
+}
+
+fn srgb_gamma(v: f32) -> u8 {
+    let v = v.clamp(0.0, 1.0);
+    let v = if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (v * 255.0).round() as u8
+}
+
+// Each 2x2 block of the color filter array becomes one pixel.
+fn demosaic(data: &[u8]) -> R<DynamicImage> {
+    let _s = crate::stats::ScopedDuration::new("raw::demosaic");
+
+    let raw = rawloader::decode(&mut &data[..]).map_err(|e| E::RawError(e.to_string()))?;
+
+    let values: Vec<f32> = match &raw.data {
+        rawloader::RawImageData::Integer(values) => values.iter().map(|&v| f32::from(v)).collect(),
+        rawloader::RawImageData::Float(values) => values.clone(),
+    };
+
+    let [top, right, bottom, left] = raw.crops;
+    let width = raw.width.saturating_sub(left + right);
+    let height = raw.height.saturating_sub(top + bottom);
+
+    // Linear raws are already demosaiced.
+    let (step, cpp) = if raw.cpp == 3 { (1, 3) } else { (2, 1) };
+    if width < step || height < step || values.len() < raw.width * raw.height * cpp {
+        return Err(E::RawError(String::from("no image data")));
+    }
+
+    let wb = {
+        let green = raw.wb_coeffs[1];
+        let mut wb = [1.0f32; 4];
+        for (c, v) in wb.iter_mut().enumerate() {
+            let coeff = raw.wb_coeffs[c] / green;
+            if coeff.is_finite() && coeff > 0.0 {
+                *v = coeff;
+            }
+        }
+        wb
+    };
+
+    let normalized = |row: usize, col: usize, c: usize, channel: usize| {
+        let v = values[(row * raw.width + col) * cpp + channel];
+        let black = f32::from(raw.blacklevels[c]);
+        let white = f32::from(raw.whitelevels[c]);
+        ((v - black) / (white - black).max(1.0)).max(0.0) * wb[c]
+    };
+
+    let matrix = srgb_from_camera(&raw.xyz_to_cam);
+
+    let (w, h) = ((width / step) as u32, (height / step) as u32);
+    let mut ret = RgbImage::new(w, h);
+
+    for (x, y, pixel) in ret.enumerate_pixels_mut() {
+        let (row, col) = (top + y as usize * step, left + x as usize * step);
+
+        let mut cam = [0.0f32; 3];
+        if cpp == 3 {
+            for (c, v) in cam.iter_mut().enumerate() {
+                *v = normalized(row, col, c, c);
+            }
+        } else {
+            let mut counts = [0u32; 3];
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    // The fourth color is a second green.
+                    let c = raw.cfa.color_at(row + dy, col + dx);
+                    let channel = if c == 3 { 1 } else { c.min(2) };
+                    cam[channel] += normalized(row + dy, col + dx, c, 0);
+                    counts[channel] += 1;
+                }
+            }
+            for (v, &count) in cam.iter_mut().zip(&counts) {
+                *v /= count.max(1) as f32;
+            }
+        }
+
+        let rgb = match matrix {
+            Some(m) => [0, 1, 2].map(|i| (0..3).map(|j| m[i][j] * cam[j]).sum::<f32>()),
+            None => cam,
+        };
+
+        *pixel = ::image::Rgb(rgb.map(srgb_gamma));
+    }
+
+    Ok(DynamicImage::ImageRgb8(ret))
+}
+
+#[test]
+fn embedded_preview() {
+    assert!(is_raw("/a/b.NEF"));
+    assert!(!is_raw("/a/b.jpg"));
+
+    let mut jpeg = Vec::new();
+    DynamicImage::new_rgb8(MIN_PREVIEW_SIZE, 8)
+        .write_to(&mut jpeg, ::image::ImageOutputFormat::JPEG(90))
+        .unwrap();
+
+    // Big endian header, IFD0 at 8 pointing at the JPEG right after it.
+    let jpeg_offset = 8 + 2 + 2 * 12 + 4;
+    let mut tiff = b"MM\0*\0\0\0\x08\0\x02".to_vec();
+    for &(tag, value) in &[
+        (TAG_JPEG_OFFSET, jpeg_offset),
+        (TAG_JPEG_LENGTH, jpeg.len() as u32),
+    ] {
+        tiff.extend_from_slice(&tag.to_be_bytes());
+        tiff.extend_from_slice(&[0, 4, 0, 0, 0, 1]);
+        tiff.extend_from_slice(&value.to_be_bytes());
+    }
+    tiff.extend_from_slice(&[0; 4]);
+    assert_eq!(tiff.len(), jpeg_offset as usize);
+    tiff.extend_from_slice(&jpeg);
+
+    let image = preview(&tiff).unwrap();
+    assert_eq!(image.dimensions(), (MIN_PREVIEW_SIZE, 8));
+
+    assert!(invert([[2.0, 0.0, 0.0], [0.0, 4.0, 0.0], [0.0, 0.0, 1.0]])
+        .is_some_and(|m| m == [[0.5, 0.0, 0.0], [0.0, 0.25, 0.0], [0.0, 0.0, 1.0]]));
+}
@@ -0,0 +1,409 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Sled only lets one process open a database. The process that gets the lock serves it over a unix
+// socket next to the database and every other pix instance forwards its database calls there.
+
+use crate::database::{Data, Database, EvictStats, Failure, FsckStats, GcStats};
+use crate::{File, Metadata, TileRef, E, R};
+use bincode::{deserialize, serialize};
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub fn socket_path(db_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.sock", db_path))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    GetMetadata(File, bool),
+    SetMetadata(File, Metadata, bool),
+    Get(TileRef),
+    Set(TileRef, Vec<u8>),
+    Remove(TileRef),
+    Reserve,
+    Release(u64),
+    FlushAccessTimes,
+    Evict(u64),
+    GcDue(u64),
+    Gc,
+    Fsck(bool),
+    Files,
+    GetFailure(File),
+    SetFailure(File, Failure),
+    ClearFailures,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    Metadata(Option<Metadata>),
+    Data(Option<Vec<u8>>),
+    Id(u64),
+    Bool(bool),
+    EvictStats(EvictStats),
+    GcStats(GcStats),
+    FsckStats(FsckStats),
+    Files(Vec<File>),
+    Failure(Option<Failure>),
+    Count(usize),
+    Ok,
+    Err(String),
+}
+
+// Largest frame either side accepts, well above the file list of a big library. The length comes
+// off the socket, so it's checked before allocating.
+static MAX_FRAME_BYTES: usize = 256 << 20;
+
+fn write_frame<T: serde::Serialize>(stream: &mut UnixStream, v: &T) -> R<()> {
+    let encoded = serialize(v).map_err(E::EncodeError)?;
+    if encoded.len() > MAX_FRAME_BYTES {
+        return Err(E::RemoteError(format!(
+            "frame of {} bytes is too large",
+            encoded.len()
+        )));
+    }
+    stream
+        .write_all(&(encoded.len() as u32).to_le_bytes())
+        .map_err(E::IoError)?;
+    stream.write_all(&encoded).map_err(E::IoError)?;
+    Ok(())
+}
+
+fn read_frame<T: serde::de::DeserializeOwned>(stream: &mut UnixStream) -> R<T> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).map_err(E::IoError)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(E::RemoteError(format!(
+            "frame of {} bytes is too large",
+            len
+        )));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).map_err(E::IoError)?;
+    deserialize(&buf).map_err(E::DecodeError)
+}
+
+/// Serves a database to other pix instances until dropped.
+pub struct Server {
+    path: PathBuf,
+}
+
+impl Server {
+    pub fn start(db: Arc<Database>, path: &Path) -> R<Self> {
+        // Whoever bound it last no longer holds the database lock.
+        if path.exists() {
+            std::fs::remove_file(path).map_err(E::IoError)?;
+        }
+
+        let listener = UnixListener::bind(path).map_err(E::IoError)?;
+        info!("Serving database on {:?}", path);
+
+        std::thread::Builder::new()
+            .name("remote".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let db = Arc::clone(&db);
+                            std::thread::spawn(move || Self::handle(&db, stream));
+                        }
+                        Err(e) => error!("remote accept: {:?}", e),
+                    }
+                }
+            })
+            .map_err(E::IoError)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+
+    fn handle(db: &Database, mut stream: UnixStream) {
+        loop {
+            let req: Request = match read_frame(&mut stream) {
+                Ok(req) => req,
+                // Client hung up.
+                Err(E::IoError(_)) => return,
+                Err(e) => {
+                    error!("remote read: {:?}", e);
+                    return;
+                }
+            };
+
+            let resp = Self::dispatch(db, req).unwrap_or_else(|e| Response::Err(e.to_string()));
+
+            if let Err(e) = write_frame(&mut stream, &resp) {
+                error!("remote write: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    fn dispatch(db: &Database, req: Request) -> R<Response> {
+        Ok(match req {
+            Request::GetMetadata(file, content_keys) => {
+                Response::Metadata(db.get_metadata_keyed(&file, content_keys)?)
+            }
+            Request::SetMetadata(file, metadata, content_keys) => {
+                db.set_metadata_keyed(&file, &metadata, content_keys)?;
+                Response::Ok
+            }
+            Request::Get(tile_ref) => Response::Data(db.get(tile_ref)?.map(|d| d.to_vec())),
+            Request::Set(tile_ref, data) => {
+                db.set(tile_ref, &data)?;
+                Response::Ok
+            }
+            Request::Remove(tile_ref) => {
+                db.remove(tile_ref)?;
+                Response::Ok
+            }
+            Request::Reserve => Response::Id(db.reserve()?),
+            Request::Release(id) => {
+                db.release(id)?;
+                Response::Ok
+            }
+            Request::FlushAccessTimes => {
+                db.flush_access_times()?;
+                Response::Ok
+            }
+            Request::Evict(max_bytes) => Response::EvictStats(db.evict(max_bytes)?),
+            Request::GcDue(interval) => Response::Bool(db.gc_due(interval)?),
+            Request::Gc => Response::GcStats(db.gc()?),
+            Request::Fsck(repair) => Response::FsckStats(db.fsck(repair)?),
+            Request::Files => Response::Files(db.files()?),
+            Request::GetFailure(file) => Response::Failure(db.get_failure(&file)?),
+            Request::SetFailure(file, failure) => {
+                db.set_failure(&file, &failure)?;
+                Response::Ok
+            }
+            Request::ClearFailures => Response::Count(db.clear_failures()?),
+        })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Forwards database calls to the instance serving the database.
+pub struct Client {
+    path: PathBuf,
+
+    // Idle connections. Each call takes one (or opens a new one) so calls from different threads
+    // don't serialize behind each other.
+    streams: Mutex<Vec<UnixStream>>,
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> R<Self> {
+        let stream = UnixStream::connect(path).map_err(E::IoError)?;
+        Ok(Self {
+            path: path.to_owned(),
+            streams: Mutex::new(vec![stream]),
+        })
+    }
+
+    fn call(&self, req: Request) -> R<Response> {
+        let stream = self.streams.lock().unwrap().pop();
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => UnixStream::connect(&self.path).map_err(E::IoError)?,
+        };
+
+        write_frame(&mut stream, &req)?;
+        let resp = read_frame(&mut stream)?;
+
+        self.streams.lock().unwrap().push(stream);
+
+        match resp {
+            Response::Err(e) => Err(E::RemoteError(e)),
+            resp => Ok(resp),
+        }
+    }
+
+    fn unexpected(resp: Response) -> E {
+        E::RemoteError(format!("unexpected response: {:?}", resp))
+    }
+
+    fn call_ok(&self, req: Request) -> R<()> {
+        match self.call(req)? {
+            Response::Ok => Ok(()),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn get_metadata(&self, file: &File, content_keys: bool) -> R<Option<Metadata>> {
+        match self.call(Request::GetMetadata(file.clone(), content_keys))? {
+            Response::Metadata(metadata) => Ok(metadata),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn set_metadata(&self, file: &File, metadata: &Metadata, content_keys: bool) -> R<()> {
+        self.call_ok(Request::SetMetadata(
+            file.clone(),
+            metadata.clone(),
+            content_keys,
+        ))
+    }
+
+    pub fn get_failure(&self, file: &File) -> R<Option<Failure>> {
+        match self.call(Request::GetFailure(file.clone()))? {
+            Response::Failure(failure) => Ok(failure),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn set_failure(&self, file: &File, failure: &Failure) -> R<()> {
+        self.call_ok(Request::SetFailure(file.clone(), failure.clone()))
+    }
+
+    pub fn clear_failures(&self) -> R<usize> {
+        match self.call(Request::ClearFailures)? {
+            Response::Count(n) => Ok(n),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn get(&self, tile_ref: TileRef) -> R<Option<Data>> {
+        match self.call(Request::Get(tile_ref))? {
+            Response::Data(data) => Ok(data.map(Data::from)),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn set(&self, tile_ref: TileRef, data: &[u8]) -> R<()> {
+        self.call_ok(Request::Set(tile_ref, data.to_vec()))
+    }
+
+    pub fn remove(&self, tile_ref: TileRef) -> R<()> {
+        self.call_ok(Request::Remove(tile_ref))
+    }
+
+    pub fn reserve(&self) -> R<u64> {
+        match self.call(Request::Reserve)? {
+            Response::Id(id) => Ok(id),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn release(&self, id: u64) -> R<()> {
+        self.call_ok(Request::Release(id))
+    }
+
+    pub fn flush_access_times(&self) -> R<()> {
+        self.call_ok(Request::FlushAccessTimes)
+    }
+
+    pub fn evict(&self, max_bytes: u64) -> R<EvictStats> {
+        match self.call(Request::Evict(max_bytes))? {
+            Response::EvictStats(stats) => Ok(stats),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn gc_due(&self, interval: u64) -> R<bool> {
+        match self.call(Request::GcDue(interval))? {
+            Response::Bool(due) => Ok(due),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn gc(&self) -> R<GcStats> {
+        match self.call(Request::Gc)? {
+            Response::GcStats(stats) => Ok(stats),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn files(&self) -> R<Vec<File>> {
+        match self.call(Request::Files)? {
+            Response::Files(files) => Ok(files),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+
+    pub fn fsck(&self, repair: bool) -> R<FsckStats> {
+        match self.call(Request::Fsck(repair))? {
+            Response::FsckStats(stats) => Ok(stats),
+            resp => Err(Self::unexpected(resp)),
+        }
+    }
+}
+
+#[test]
+fn forwards_to_owner() {
+    use crate::store::Backend;
+
+    let dir = crate::store::test_path("remote");
+
+    let db_path = dir.join("db");
+    let db_path = db_path.to_str().unwrap();
+
+    let owner = Arc::new(Database::open(Backend::Sled, db_path).unwrap());
+    assert!(!owner.is_remote());
+    let _server = Server::start(Arc::clone(&owner), &socket_path(db_path)).unwrap();
+
+    let db = Database::open(Backend::Sled, db_path).unwrap();
+    assert!(db.is_remote());
+
+    let file = File {
+        path: String::from("/remote"),
+        ..Default::default()
+    };
+    let tile_ref = TileRef::new(crate::Pow2(3), db.reserve().unwrap(), 0);
+    let metadata = Metadata {
+        thumbs: vec![crate::Thumb {
+            img_size: [8, 8],
+            tile_refs: vec![tile_ref],
+            format: crate::TileFormat::Jpeg,
+            alpha: false,
+        }],
+        icc_converted: false,
+        animation: None,
+        duration_ms: None,
+    };
+
+    db.set(tile_ref, b"tile").unwrap();
+    db.set_metadata(&file, &metadata).unwrap();
+
+    assert_eq!(owner.get_metadata(&file).unwrap(), Some(metadata));
+    assert_eq!(&*db.get(tile_ref).unwrap().unwrap(), b"tile");
+    assert!(db
+        .get(TileRef::new(crate::Pow2(3), 1234, 0))
+        .unwrap()
+        .is_none());
+
+    let failure = Failure::of(&E::SvgError(String::from("bad"))).unwrap();
+    db.set_failure(&file, &failure).unwrap();
+    assert_eq!(owner.get_failure(&file).unwrap(), Some(failure));
+    assert_eq!(db.clear_failures().unwrap(), 1);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rejects_large_frames() {
+    let (mut a, mut b) = UnixStream::pair().unwrap();
+    a.write_all(&u32::MAX.to_le_bytes()).unwrap();
+    match read_frame::<Request>(&mut b) {
+        Err(E::RemoteError(_)) => {}
+        res => panic!("{:?}", res),
+    }
+}
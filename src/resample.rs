@@ -0,0 +1,407 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Downsampling of pyramid levels. The filter and whether to average in linear light are set by
+// flags, and can be overridden for the images of a directory by a `.pix` file of `key=value`
+// lines in it:
+//
+//     resample_filter=nearest
+//     linear_resample=false
+
+use ::image::{DynamicImage, GenericImageView, ImageBuffer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+static DIR_CONFIG: &str = ".pix";
+
+lazy_static! {
+    // By directory, with when their file was modified so changes are read again.
+    static ref DIR_OVERRIDES: Mutex<BTreeMap<PathBuf, (Option<SystemTime>, Overrides)>> =
+        Mutex::new(BTreeMap::new());
+
+    static ref TO_LINEAR: Vec<u16> = (0..256)
+        .map(|v| (srgb_to_linear(v as f32 / 255.0) * 65535.0).round() as u16)
+        .collect();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filter {
+    #[default]
+    Lanczos3,
+    CatmullRom,
+    /// Averages the pixels each output pixel covers.
+    Box,
+    /// Keeps hard edges, for pixel art.
+    Nearest,
+}
+
+impl Filter {
+    pub const NAMES: &'static [&'static str] = &["lanczos3", "catmullrom", "box", "nearest"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lanczos3" => Some(Filter::Lanczos3),
+            "catmullrom" => Some(Filter::CatmullRom),
+            "box" => Some(Filter::Box),
+            "nearest" => Some(Filter::Nearest),
+            _ => None,
+        }
+    }
+
+    /// Parses `FILTER[,FILTER...]`.
+    pub fn parse_list(list: &str) -> Option<Vec<Self>> {
+        list.split(',')
+            .map(|name| Self::from_name(name.trim()))
+            .collect()
+    }
+
+    // None for nearest, which the resize crate can't downsample with.
+    fn resize_type(self) -> Option<resize::Type> {
+        match self {
+            Filter::Lanczos3 => Some(resize::Type::Lanczos3),
+            Filter::CatmullRom => Some(resize::Type::Catrom),
+            Filter::Box => Some(resize::Type::Custom(resize::Filter::new(
+                Box::new(|x: f32| if x.abs() <= 0.5 { 1.0 } else { 0.0 }),
+                0.5,
+            ))),
+            Filter::Nearest => None,
+        }
+    }
+}
+
+/// How the levels of an image are made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resampling {
+    // Filter of each pyramid level starting from the original size, the last entry applies to all
+    // smaller levels.
+    pub filters: Vec<Filter>,
+
+    // Average in linear light rather than on sRGB values, so fine detail keeps its brightness.
+    pub linear: bool,
+}
+
+impl Default for Resampling {
+    fn default() -> Self {
+        Self {
+            filters: vec![Filter::default()],
+            linear: false,
+        }
+    }
+}
+
+// Settings from a directory's `.pix` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Overrides {
+    filters: Option<Vec<Filter>>,
+    linear: Option<bool>,
+}
+
+impl Overrides {
+    fn read(dir: &Path) -> Self {
+        let path = dir.join(DIR_CONFIG);
+        match std::fs::read_to_string(&path) {
+            Ok(config) => Self::parse(&config, &path),
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn parse(config: &str, path: &Path) -> Self {
+        let mut ret = Self::default();
+        for line in config.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+                None => (line, ""),
+            };
+            match key {
+                "resample_filter" => match Filter::parse_list(value) {
+                    Some(filters) => ret.filters = Some(filters),
+                    None => error!("{:?}: unknown filter {:?}", path, value),
+                },
+                "linear_resample" => match value.parse() {
+                    Ok(linear) => ret.linear = Some(linear),
+                    Err(_) => error!("{:?}: linear_resample takes true or false", path),
+                },
+                _ => error!("{:?}: unknown setting {:?}", path, key),
+            }
+        }
+        ret
+    }
+}
+
+impl Resampling {
+    pub fn filter(&self, level: usize) -> Filter {
+        let last = self.filters.last().copied().unwrap_or_default();
+        self.filters.get(level).copied().unwrap_or(last)
+    }
+
+    /// The settings for the image at `path`, with its directory's overrides applied.
+    pub fn for_path(&self, path: &str) -> Self {
+        let dir = match Path::new(path).parent() {
+            Some(dir) => dir,
+            None => return self.clone(),
+        };
+
+        let modified = std::fs::metadata(dir.join(DIR_CONFIG))
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        let overrides = {
+            let mut cache = DIR_OVERRIDES.lock().unwrap();
+            match cache.get(dir) {
+                Some((read, overrides)) if *read == modified => overrides.clone(),
+                _ => {
+                    let overrides = Overrides::read(dir);
+                    cache.insert(dir.to_path_buf(), (modified, overrides.clone()));
+                    overrides
+                }
+            }
+        };
+
+        self.with(overrides)
+    }
+
+    fn with(&self, overrides: Overrides) -> Self {
+        Self {
+            filters: overrides.filters.unwrap_or_else(|| self.filters.clone()),
+            linear: overrides.linear.unwrap_or(self.linear),
+        }
+    }
+}
+
+/// The size of a `w` by `h` image scaled down to fit in a `bucket` sized square.
+pub fn fit(w: u32, h: u32, bucket: u32) -> (u32, u32) {
+    let (w, h, bucket) = (u64::from(w), u64::from(h), u64::from(bucket));
+    let (w2, h2) = if w >= h {
+        (bucket, h * bucket / w)
+    } else {
+        (w * bucket / h, bucket)
+    };
+    (std::cmp::max(w2, 1) as u32, std::cmp::max(h2, 1) as u32)
+}
+
+/// Scales `image` down to fit in a `bucket` sized square.
+pub fn resample(image: &DynamicImage, bucket: u32, filter: Filter, linear: bool) -> DynamicImage {
+    let _s = crate::stats::ScopedDuration::new("resample");
+
+    let (w, h) = image.dimensions();
+    let (w2, h2) = fit(w, h, bucket);
+
+    let t = match filter.resize_type() {
+        Some(t) => t,
+        None => return image.resize_exact(w2, h2, ::image::FilterType::Nearest),
+    };
+
+    let src = (w as usize, h as usize);
+    let dst = (w2 as usize, h2 as usize);
+
+    match image {
+        DynamicImage::ImageRgb8(rgb) if linear => {
+            let pixels = resize(&to_linear(rgb, 3), src, dst, resize::Pixel::RGB48, t);
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(w2, h2, from_linear(&pixels, 3)).unwrap())
+        }
+        DynamicImage::ImageRgb8(rgb) => {
+            let pixels = resize(rgb, src, dst, resize::Pixel::RGB24, t);
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(w2, h2, pixels).unwrap())
+        }
+        _ => {
+            let converted;
+            let rgba = match image {
+                DynamicImage::ImageRgba8(rgba) => rgba,
+                _ => {
+                    converted = image.to_rgba();
+                    &converted
+                }
+            };
+            let pixels = if linear {
+                let pixels = resize(&to_linear(rgba, 4), src, dst, resize::Pixel::RGBA64, t);
+                from_linear(&pixels, 4)
+            } else {
+                resize(rgba, src, dst, resize::Pixel::RGBA, t)
+            };
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(w2, h2, pixels).unwrap())
+        }
+    }
+}
+
+/// Halves a pair of rows of RGBA pixels (the same row twice at odd bottom edges) in both
+/// directions. Nearest keeps the top left pixel of each 2x2 block, the other filters average it,
+/// which is all a box filter does at this scale.
+pub fn halve(a: &[u8], b: &[u8], filter: Filter, linear: bool) -> Vec<u8> {
+    let width = a.len() / 4;
+    let mut out = Vec::with_capacity(4 * width.div_ceil(2));
+    for x in (0..width).step_by(2) {
+        if filter == Filter::Nearest {
+            out.extend_from_slice(&a[4 * x..4 * x + 4]);
+            continue;
+        }
+
+        // Odd right edges are averaged with themselves.
+        let x1 = std::cmp::min(x + 1, width - 1);
+        for c in 0..4 {
+            let block = [a[4 * x + c], a[4 * x1 + c], b[4 * x + c], b[4 * x1 + c]];
+            out.push(if linear && c != 3 {
+                let sum: u32 = block
+                    .iter()
+                    .map(|&v| u32::from(TO_LINEAR[v as usize]))
+                    .sum();
+                linear_to_u8(((sum + 2) / 4) as u16)
+            } else {
+                let sum: u32 = block.iter().copied().map(u32::from).sum();
+                ((sum + 2) / 4) as u8
+            });
+        }
+    }
+    out
+}
+
+fn resize<P: resize::PixelFormat>(
+    pixels: &[P::Subpixel],
+    (w, h): (usize, usize),
+    (w2, h2): (usize, usize),
+    format: P,
+    t: resize::Type,
+) -> Vec<P::Subpixel>
+where
+    P::Subpixel: Default,
+{
+    let mut dst = vec![P::Subpixel::default(); w2 * h2 * format.get_ncomponents()];
+    resize::resize(w, h, w2, h2, format, t, pixels, &mut dst);
+    dst
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Widens 8 bit sRGB samples to 16 bit linear ones. Alpha (the 4th channel) is already linear.
+fn to_linear(pixels: &[u8], channels: usize) -> Vec<u16> {
+    pixels
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| {
+            if i % channels == 3 {
+                u16::from(v) * 257
+            } else {
+                TO_LINEAR[v as usize]
+            }
+        })
+        .collect()
+}
+
+fn from_linear(pixels: &[u16], channels: usize) -> Vec<u8> {
+    pixels
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| {
+            if i % channels == 3 {
+                (f32::from(v) / 257.0).round() as u8
+            } else {
+                linear_to_u8(v)
+            }
+        })
+        .collect()
+}
+
+fn linear_to_u8(v: u16) -> u8 {
+    (linear_to_srgb(f32::from(v) / 65535.0) * 255.0)
+        .round()
+        .clamp(0.0, 255.0) as u8
+}
+
+#[test]
+fn resample_levels() {
+    assert_eq!(fit(300, 200, 64), (64, 42));
+    assert_eq!(fit(2, 1000, 8), (1, 8));
+
+    // Black and white stripes.
+    let stripes = DynamicImage::ImageRgb8(ImageBuffer::from_fn(16, 16, |x, _| {
+        ::image::Rgb([if x % 2 == 0 { 0 } else { 255 }; 3])
+    }));
+
+    let gray = |image: DynamicImage| image.to_rgb().get_pixel(2, 2)[0];
+    assert_eq!(gray(resample(&stripes, 8, Filter::Box, false)), 128);
+    // Half the light is half as bright on screen.
+    assert_eq!(gray(resample(&stripes, 8, Filter::Box, true)), 188);
+    let nearest = gray(resample(&stripes, 8, Filter::Nearest, false));
+    assert!(nearest == 0 || nearest == 255);
+
+    let alpha =
+        DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, ::image::Rgba([9, 9, 9, 9])));
+    let small = resample(&alpha, 2, Filter::Lanczos3, true);
+    assert_eq!(small.dimensions(), (2, 2));
+    assert_eq!(
+        small.to_rgba().get_pixel(1, 1),
+        &::image::Rgba([9, 9, 9, 9])
+    );
+
+    let defaults = Resampling {
+        filters: vec![Filter::Lanczos3, Filter::Box],
+        linear: false,
+    };
+    // Streamed levels are halved a pair of rows at a time.
+    let (a, b) = ([0, 0, 0, 0, 4, 4, 4, 4, 9, 9, 9, 9], [8; 12]);
+    assert_eq!(
+        halve(&a, &b, Filter::Lanczos3, false),
+        vec![5, 5, 5, 5, 9, 9, 9, 9]
+    );
+    assert_eq!(
+        halve(&a, &b, Filter::Nearest, false),
+        vec![0, 0, 0, 0, 9, 9, 9, 9]
+    );
+    assert_eq!(
+        halve(
+            &[0, 0, 0, 0, 255, 255, 255, 255],
+            &[0; 8],
+            Filter::Box,
+            true
+        ),
+        vec![137, 137, 137, 64]
+    );
+
+    assert_eq!(defaults.filter(0), Filter::Lanczos3);
+    assert_eq!(defaults.filter(5), Filter::Box);
+
+    let overrides = Overrides::parse(
+        "# pixel art\nresample_filter = nearest\nlinear_resample=true\n",
+        Path::new(".pix"),
+    );
+    assert_eq!(
+        defaults.with(overrides),
+        Resampling {
+            filters: vec![Filter::Nearest],
+            linear: true,
+        }
+    );
+    assert_eq!(
+        defaults.with(Overrides::parse("bogus\n", Path::new(".pix"))),
+        defaults
+    );
+}
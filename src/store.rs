@@ -0,0 +1,220 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod file_store;
+mod memory_store;
+mod sled_store;
+
+pub use file_store::FileStore;
+pub use memory_store::MemoryStore;
+pub use sled_store::SledStore;
+
+use crate::{TileRef, E, R};
+use std::io::ErrorKind;
+use std::ops::Deref;
+use std::path::Path;
+
+// Tile indices must fit in the 40 bits TileRef reserves for them.
+static ID_LIMIT: u64 = 1u64 << 40;
+
+// Wrap database types.
+pub struct Data(sled::IVec);
+
+impl From<Vec<u8>> for Data {
+    fn from(v: Vec<u8>) -> Self {
+        Data(sled::IVec::from(v))
+    }
+}
+
+impl From<sled::IVec> for Data {
+    fn from(v: sled::IVec) -> Self {
+        Data(v)
+    }
+}
+
+impl Deref for Data {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        self.0.deref()
+    }
+}
+
+pub type Iter<'a, T> = Box<dyn Iterator<Item = R<T>> + 'a>;
+
+/// Storage behind `Database`. `Database` owns the key layout and encodings, stores just persist
+/// bytes.
+pub trait ThumbStore: Send + Sync {
+    /// Gets a metadata (or other bookkeeping) record.
+    fn get(&self, key: &[u8]) -> R<Option<Data>>;
+
+    fn set(&self, key: &[u8], value: &[u8]) -> R<()>;
+
+    fn remove(&self, key: &[u8]) -> R<()>;
+
+    /// Records with keys starting with `prefix`, in no particular order.
+    fn scan(&self, prefix: &[u8]) -> Iter<'_, (Data, Data)>;
+
+    fn get_tile(&self, tile_ref: TileRef) -> R<Option<Data>>;
+
+    fn set_tile(&self, tile_ref: TileRef, data: &[u8]) -> R<()>;
+
+    /// Returns the number of bytes freed.
+    fn remove_tile(&self, tile_ref: TileRef) -> R<u64>;
+
+    /// Every tile and the number of bytes it occupies.
+    fn tiles(&self) -> Iter<'_, (TileRef, u64)>;
+
+    /// Allocates a tile index, preferring released ones.
+    fn reserve_id(&self) -> R<u64>;
+
+    /// Makes an index that has no tiles available to `reserve_id` again.
+    fn release_id(&self, id: u64) -> R<()>;
+
+    fn flush(&self) -> R<()>;
+
+    /// Bytes the store takes up on disk (or in memory).
+    fn size_on_disk(&self) -> R<u64>;
+}
+
+// Space allocated to the files under `path`.
+fn dir_size(path: &Path) -> R<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut ret = 0;
+    for entry in walkdir::WalkDir::new(path) {
+        let metadata = match entry.and_then(|entry| entry.metadata()) {
+            Ok(metadata) => metadata,
+            // Removed since its directory was listed.
+            Err(ref e) if e.io_error().map(std::io::Error::kind) == Some(ErrorKind::NotFound) => {
+                continue
+            }
+            Err(e) => return Err(E::IoError(e.into())),
+        };
+        if metadata.is_file() {
+            ret += metadata.blocks() * 512;
+        }
+    }
+    Ok(ret)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sled,
+    Files,
+    Memory,
+}
+
+impl Backend {
+    pub const NAMES: &'static [&'static str] = &["sled", "files", "memory"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sled" => Some(Backend::Sled),
+            "files" => Some(Backend::Files),
+            "memory" => Some(Backend::Memory),
+            _ => None,
+        }
+    }
+
+    /// Default location under the user's cache directory.
+    pub fn default_path(self) -> &'static str {
+        match self {
+            Backend::Sled => "pix/thumbs.db",
+            Backend::Files => "pix/thumbs",
+            Backend::Memory => "",
+        }
+    }
+}
+
+pub fn open(backend: Backend, path: &str) -> R<Box<dyn ThumbStore>> {
+    Ok(match backend {
+        Backend::Sled => Box::new(SledStore::open(path)?),
+        Backend::Files => Box::new(FileStore::open(path)?),
+        Backend::Memory => Box::new(MemoryStore::default()),
+    })
+}
+
+#[cfg(test)]
+fn check_store(store: &dyn ThumbStore) {
+    use crate::Pow2;
+
+    assert!(store.get(b"Ma").unwrap().is_none());
+    store.set(b"Ma", b"1").unwrap();
+    store.set(b"Mb", b"2").unwrap();
+    store.set(b"Ac", b"3").unwrap();
+    assert_eq!(&*store.get(b"Ma").unwrap().unwrap(), b"1");
+
+    let mut scanned: Vec<(Vec<u8>, Vec<u8>)> = store
+        .scan(b"M")
+        .map(|kv| {
+            let (k, v) = kv.unwrap();
+            (k.to_vec(), v.to_vec())
+        })
+        .collect();
+    scanned.sort();
+    assert_eq!(
+        scanned,
+        vec![
+            (b"Ma".to_vec(), b"1".to_vec()),
+            (b"Mb".to_vec(), b"2".to_vec())
+        ]
+    );
+
+    store.remove(b"Ma").unwrap();
+    store.remove(b"Ma").unwrap();
+    assert!(store.get(b"Ma").unwrap().is_none());
+
+    let tile_ref = TileRef::new(Pow2(3), 7, 1);
+    assert!(store.get_tile(tile_ref).unwrap().is_none());
+    store.set_tile(tile_ref, b"tile").unwrap();
+    assert_eq!(&*store.get_tile(tile_ref).unwrap().unwrap(), b"tile");
+    let tiles: Vec<TileRef> = store.tiles().map(|t| t.unwrap().0).collect();
+    assert_eq!(tiles, vec![tile_ref]);
+    assert!(store.remove_tile(tile_ref).unwrap() > 0);
+    assert_eq!(store.remove_tile(tile_ref).unwrap(), 0);
+    assert_eq!(store.tiles().count(), 0);
+
+    assert_eq!(store.reserve_id().unwrap(), 0);
+    assert_eq!(store.reserve_id().unwrap(), 1);
+    store.release_id(0).unwrap();
+    assert_eq!(store.reserve_id().unwrap(), 0);
+    assert_eq!(store.reserve_id().unwrap(), 2);
+
+    store.flush().unwrap();
+    assert!(store.size_on_disk().unwrap() > 0);
+}
+
+#[cfg(test)]
+pub fn test_path(name: &str) -> std::path::PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!("pix-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+#[test]
+fn stores() {
+    check_store(&MemoryStore::default());
+
+    let dir = test_path("stores");
+    check_store(&SledStore::open(dir.join("sled").to_str().unwrap()).unwrap());
+    let files = dir.join("files");
+    let store = FileStore::open(files.to_str().unwrap()).unwrap();
+    check_store(&store);
+    assert!(FileStore::open(files.to_str().unwrap()).is_err());
+    drop(store);
+    assert!(FileStore::open(files.to_str().unwrap()).is_ok());
+    let _ = std::fs::remove_dir_all(&dir);
+}
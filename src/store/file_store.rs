@@ -0,0 +1,291 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// One file per record and per tile. Writes go through a rename so readers never see a partial
+// file. Only one process opens the directory at a time, others forward to it like with sled: which
+// ids are in flight is only known to the process that reserved them, GC in another would collect
+// their tiles.
+//
+//   lock                     held by the process that has the store open
+//   records/<hash of key>    u32 key length, key, value
+//   tiles/<xx>/<tile ref>    tile data, sharded by the low byte of the tile index
+//   ids/max                  next never-used tile index
+//   ids/free/<id>            released tile indices
+
+use super::{dir_size, Data, Iter, ThumbStore, ID_LIMIT};
+use crate::{TileRef, E, R};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// FNV-1a, twice with different offsets so collisions are 128-bit unlikely.
+fn record_name(key: &[u8]) -> String {
+    let fnv = |offset: u64| {
+        key.iter().fold(offset, |h, &b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01B3)
+        })
+    };
+    format!(
+        "{:016x}{:016x}",
+        fnv(0xCBF2_9CE4_8422_2325),
+        fnv(0x6C62_272E_07BB_0142)
+    )
+}
+
+fn not_found_is_none<T>(res: std::io::Result<T>) -> R<Option<T>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(E::IoError(e)),
+    }
+}
+
+fn decode_record(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&buf[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    if buf.len() < 4 + len {
+        return None;
+    }
+    Some(buf[4..].split_at(len))
+}
+
+pub struct FileStore {
+    root: PathBuf,
+    tmp_counter: AtomicUsize,
+
+    // Unlocked when closed.
+    _lock: fs::File,
+}
+
+impl FileStore {
+    pub fn open(path: &str) -> R<Self> {
+        let root = PathBuf::from(path);
+        for dir in &["records", "tiles", "ids/free", "tmp"] {
+            fs::create_dir_all(root.join(dir)).map_err(E::IoError)?;
+        }
+
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(root.join("lock"))
+            .map_err(E::IoError)?;
+        fs2::FileExt::try_lock_exclusive(&lock).map_err(E::IoError)?;
+
+        Ok(Self {
+            root,
+            tmp_counter: AtomicUsize::new(0),
+            _lock: lock,
+        })
+    }
+
+    fn record_path(&self, key: &[u8]) -> PathBuf {
+        self.root.join("records").join(record_name(key))
+    }
+
+    fn tile_path(&self, tile_ref: TileRef) -> PathBuf {
+        self.root
+            .join("tiles")
+            .join(format!("{:02x}", tile_ref.index() & 0xFF))
+            .join(format!("{:016x}", tile_ref.0))
+    }
+
+    fn free_path(&self, id: u64) -> PathBuf {
+        self.root.join("ids/free").join(format!("{:016x}", id))
+    }
+
+    // Readers never see a partially written file.
+    fn write_atomic(&self, path: &Path, parts: &[&[u8]]) -> R<()> {
+        let tmp = self.root.join("tmp").join(format!(
+            "{}-{}",
+            std::process::id(),
+            self.tmp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, parts.concat()).map_err(E::IoError)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(E::IoError)?;
+        }
+        fs::rename(&tmp, path).map_err(E::IoError)
+    }
+
+    fn read_record(path: &Path) -> R<Option<(Data, Data)>> {
+        let buf = match not_found_is_none(fs::read(path))? {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+        let (k, v) = decode_record(&buf)
+            .ok_or_else(|| E::CorruptData(path.to_string_lossy().into_owned()))?;
+        Ok(Some((Data::from(k.to_vec()), Data::from(v.to_vec()))))
+    }
+
+    fn read_dir(path: &Path) -> R<Vec<PathBuf>> {
+        let mut ret = Vec::new();
+        for entry in fs::read_dir(path).map_err(E::IoError)? {
+            ret.push(entry.map_err(E::IoError)?.path());
+        }
+        Ok(ret)
+    }
+
+    fn next_id(&self) -> R<u64> {
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.root.join("ids/lock"))
+            .map_err(E::IoError)?;
+        fs2::FileExt::lock_exclusive(&lock).map_err(E::IoError)?;
+
+        let max_path = self.root.join("ids/max");
+        let max_id = match not_found_is_none(fs::read_to_string(&max_path))? {
+            Some(v) => v
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| E::CorruptData(max_path.to_string_lossy().into_owned()))?,
+            None => 0,
+        };
+
+        let ret = if max_id + 1 >= ID_LIMIT {
+            Err(E::IdSpaceExhausted)
+        } else {
+            self.write_atomic(&max_path, &[format!("{}", max_id + 1).as_bytes()])
+                .map(|_| max_id)
+        };
+
+        fs2::FileExt::unlock(&lock).map_err(E::IoError)?;
+
+        ret
+    }
+}
+
+impl ThumbStore for FileStore {
+    fn get(&self, key: &[u8]) -> R<Option<Data>> {
+        Ok(match Self::read_record(&self.record_path(key))? {
+            Some((k, v)) if &*k == key => Some(v),
+            _ => None,
+        })
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> R<()> {
+        let len = (key.len() as u32).to_le_bytes();
+        self.write_atomic(&self.record_path(key), &[&len, key, value])
+    }
+
+    fn remove(&self, key: &[u8]) -> R<()> {
+        not_found_is_none(fs::remove_file(self.record_path(key)))?;
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Iter<'_, (Data, Data)> {
+        let paths = match Self::read_dir(&self.root.join("records")) {
+            Ok(paths) => paths,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+
+        let prefix = prefix.to_vec();
+        Box::new(paths.into_iter().filter_map(move |path| {
+            match Self::read_record(&path) {
+                Ok(Some((k, v))) if k.starts_with(&prefix) => Some(Ok((k, v))),
+                // Removed since the directory was listed.
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+
+    fn get_tile(&self, tile_ref: TileRef) -> R<Option<Data>> {
+        Ok(not_found_is_none(fs::read(self.tile_path(tile_ref)))?.map(Data::from))
+    }
+
+    fn set_tile(&self, tile_ref: TileRef, data: &[u8]) -> R<()> {
+        self.write_atomic(&self.tile_path(tile_ref), &[data])
+    }
+
+    fn remove_tile(&self, tile_ref: TileRef) -> R<u64> {
+        let path = self.tile_path(tile_ref);
+        let len = match not_found_is_none(fs::metadata(&path))? {
+            Some(metadata) => metadata.len(),
+            None => return Ok(0),
+        };
+        Ok(not_found_is_none(fs::remove_file(&path))?
+            .map(|_| len)
+            .unwrap_or(0))
+    }
+
+    fn tiles(&self) -> Iter<'_, (TileRef, u64)> {
+        let shards = match Self::read_dir(&self.root.join("tiles")) {
+            Ok(shards) => shards,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+
+        Box::new(
+            shards
+                .into_iter()
+                .flat_map(|shard| match Self::read_dir(&shard) {
+                    Ok(paths) => paths.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                })
+                .filter_map(|path| {
+                    let path = match path {
+                        Ok(path) => path,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let tile_ref = path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(|name| u64::from_str_radix(name, 16).ok())
+                        .map(TileRef)?;
+                    match not_found_is_none(fs::metadata(&path)) {
+                        Ok(Some(metadata)) => Some(Ok((tile_ref, metadata.len()))),
+                        Ok(None) => None,
+                        Err(e) => Some(Err(e)),
+                    }
+                }),
+        )
+    }
+
+    fn reserve_id(&self) -> R<u64> {
+        for path in Self::read_dir(&self.root.join("ids/free"))? {
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| u64::from_str_radix(name, 16).ok());
+
+            // Whoever manages to remove the file owns the id.
+            if let Some(id) = id {
+                if not_found_is_none(fs::remove_file(&path))?.is_some() {
+                    return Ok(id);
+                }
+            }
+        }
+
+        self.next_id()
+    }
+
+    fn release_id(&self, id: u64) -> R<()> {
+        self.write_atomic(&self.free_path(id), &[])
+    }
+
+    fn flush(&self) -> R<()> {
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> R<u64> {
+        dir_size(&self.root)
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Nothing is persisted, useful for tests and throwaway sessions.
+
+use super::{Data, Iter, ThumbStore, ID_LIMIT};
+use crate::{TileRef, E, R};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct Ids {
+    max_id: u64,
+    free: BTreeSet<u64>,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    tiles: Mutex<BTreeMap<TileRef, Vec<u8>>>,
+    ids: Mutex<Ids>,
+}
+
+impl ThumbStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> R<Option<Data>> {
+        let records = self.records.lock().unwrap();
+        Ok(records.get(key).cloned().map(Data::from))
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> R<()> {
+        let mut records = self.records.lock().unwrap();
+        records.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> R<()> {
+        self.records.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Iter<'_, (Data, Data)> {
+        let records = self.records.lock().unwrap();
+        let matches: Vec<R<(Data, Data)>> = records
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| Ok((Data::from(k.clone()), Data::from(v.clone()))))
+            .collect();
+        Box::new(matches.into_iter())
+    }
+
+    fn get_tile(&self, tile_ref: TileRef) -> R<Option<Data>> {
+        let tiles = self.tiles.lock().unwrap();
+        Ok(tiles.get(&tile_ref).cloned().map(Data::from))
+    }
+
+    fn set_tile(&self, tile_ref: TileRef, data: &[u8]) -> R<()> {
+        self.tiles.lock().unwrap().insert(tile_ref, data.to_vec());
+        Ok(())
+    }
+
+    fn remove_tile(&self, tile_ref: TileRef) -> R<u64> {
+        let removed = self.tiles.lock().unwrap().remove(&tile_ref);
+        Ok(removed.map(|v| v.len() as u64).unwrap_or(0))
+    }
+
+    fn tiles(&self) -> Iter<'_, (TileRef, u64)> {
+        let tiles = self.tiles.lock().unwrap();
+        let sizes: Vec<R<(TileRef, u64)>> = tiles
+            .iter()
+            .map(|(&tile_ref, v)| Ok((tile_ref, v.len() as u64)))
+            .collect();
+        Box::new(sizes.into_iter())
+    }
+
+    fn reserve_id(&self) -> R<u64> {
+        let mut ids = self.ids.lock().unwrap();
+
+        if let Some(&id) = ids.free.iter().next() {
+            ids.free.remove(&id);
+            return Ok(id);
+        }
+
+        if ids.max_id + 1 >= ID_LIMIT {
+            return Err(E::IdSpaceExhausted);
+        }
+
+        ids.max_id += 1;
+        Ok(ids.max_id - 1)
+    }
+
+    fn release_id(&self, id: u64) -> R<()> {
+        self.ids.lock().unwrap().free.insert(id);
+        Ok(())
+    }
+
+    fn flush(&self) -> R<()> {
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> R<u64> {
+        let records = self.records.lock().unwrap();
+        let tiles = self.tiles.lock().unwrap();
+        let records = records.iter().map(|(k, v)| k.len() + v.len());
+        let tiles = tiles.values().map(|v| 8 + v.len());
+        Ok(records.chain(tiles).sum::<usize>() as u64)
+    }
+}
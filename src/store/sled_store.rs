@@ -0,0 +1,179 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The original backend. Records and tiles share one sled keyspace so record keys must not start
+// with `T` or `F`, and `_MAX_ID` is taken.
+
+use super::{dir_size, Data, Iter, ThumbStore, ID_LIMIT};
+use crate::{TileRef, E, R};
+use std::path::PathBuf;
+
+static MAX_ID: &[u8] = b"_MAX_ID";
+static TILE_PREFIX: char = 'T';
+static FREE_PREFIX: char = 'F';
+
+fn key_for_u64(prefix: char, v: u64) -> [u8; 9] {
+    let mut k: [u8; 9] = [prefix as u8; 9];
+    k[1..9].copy_from_slice(&v.to_be_bytes());
+    k
+}
+
+fn parse_u64(prefix: char, k: &[u8]) -> Option<u64> {
+    if k.len() != 9 || k[0] != prefix as u8 {
+        return None;
+    }
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&k[1..9]);
+    Some(u64::from_be_bytes(v))
+}
+
+#[test]
+fn keys() {
+    let tile_ref = 0x0102_0304_0506_0708;
+    assert_eq!(
+        parse_u64(TILE_PREFIX, &key_for_u64(TILE_PREFIX, tile_ref)),
+        Some(tile_ref)
+    );
+    assert_eq!(parse_u64(TILE_PREFIX, b"M/here:1"), None);
+    assert_eq!(parse_u64(TILE_PREFIX, &key_for_u64(FREE_PREFIX, 1)), None);
+}
+
+pub struct SledStore {
+    db: sled::Db,
+    path: PathBuf,
+}
+
+impl SledStore {
+    pub fn open(path: &str) -> R<Self> {
+        let db = sled::Db::open(path).map_err(E::DatabaseError)?;
+        Ok(Self {
+            db,
+            path: PathBuf::from(path),
+        })
+    }
+
+    fn pop_free_id(&self) -> R<Option<u64>> {
+        loop {
+            let k = match self.db.scan_prefix([FREE_PREFIX as u8]).keys().next() {
+                Some(k) => k.map_err(E::DatabaseError)?,
+                None => return Ok(None),
+            };
+
+            // Another thread may have claimed it first.
+            if self.db.remove(&k).map_err(E::DatabaseError)?.is_some() {
+                return Ok(parse_u64(FREE_PREFIX, &k));
+            }
+        }
+    }
+
+    fn next_id(&self) -> R<u64> {
+        loop {
+            let old = self.db.get(MAX_ID).map_err(E::DatabaseError)?;
+
+            let max_id = match &old {
+                Some(v) => std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .ok_or_else(|| E::CorruptData(String::from("_MAX_ID")))?,
+                None => 0,
+            };
+
+            if max_id + 1 >= ID_LIMIT {
+                return Err(E::IdSpaceExhausted);
+            }
+
+            let new = format!("{}", max_id + 1);
+
+            if self
+                .db
+                .compare_and_swap(MAX_ID, old, Some(new.as_bytes()))
+                .map_err(E::DatabaseError)?
+                .is_ok()
+            {
+                return Ok(max_id);
+            }
+        }
+    }
+}
+
+impl ThumbStore for SledStore {
+    fn get(&self, key: &[u8]) -> R<Option<Data>> {
+        let v = self.db.get(key).map_err(E::DatabaseError)?;
+        Ok(v.map(Data::from))
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> R<()> {
+        self.db.insert(key, value).map_err(E::DatabaseError)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> R<()> {
+        self.db.remove(key).map_err(E::DatabaseError)?;
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Iter<'_, (Data, Data)> {
+        Box::new(self.db.scan_prefix(prefix).map(|kv| {
+            let (k, v) = kv.map_err(E::DatabaseError)?;
+            Ok((Data::from(k), Data::from(v)))
+        }))
+    }
+
+    fn get_tile(&self, tile_ref: TileRef) -> R<Option<Data>> {
+        self.get(&key_for_u64(TILE_PREFIX, tile_ref.0))
+    }
+
+    fn set_tile(&self, tile_ref: TileRef, data: &[u8]) -> R<()> {
+        self.set(&key_for_u64(TILE_PREFIX, tile_ref.0), data)
+    }
+
+    fn remove_tile(&self, tile_ref: TileRef) -> R<u64> {
+        let k = key_for_u64(TILE_PREFIX, tile_ref.0);
+        let v = self.db.remove(k).map_err(E::DatabaseError)?;
+        Ok(v.map(|v| (k.len() + v.len()) as u64).unwrap_or(0))
+    }
+
+    fn tiles(&self) -> Iter<'_, (TileRef, u64)> {
+        Box::new(
+            self.db
+                .scan_prefix([TILE_PREFIX as u8])
+                .filter_map(|kv| match kv {
+                    Ok((k, v)) => parse_u64(TILE_PREFIX, &k)
+                        .map(|t| Ok((TileRef(t), (k.len() + v.len()) as u64))),
+                    Err(e) => Some(Err(E::DatabaseError(e))),
+                }),
+        )
+    }
+
+    fn reserve_id(&self) -> R<u64> {
+        match self.pop_free_id()? {
+            Some(id) => Ok(id),
+            None => self.next_id(),
+        }
+    }
+
+    fn release_id(&self, id: u64) -> R<()> {
+        self.set(&key_for_u64(FREE_PREFIX, id), &[])
+    }
+
+    fn flush(&self) -> R<()> {
+        self.db.flush().map_err(E::DatabaseError)?;
+        Ok(())
+    }
+
+    // Space held by removed records is reclaimed as sled compacts its log.
+    fn size_on_disk(&self) -> R<u64> {
+        dir_size(&self.path)
+    }
+}
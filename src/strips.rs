@@ -0,0 +1,134 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Images read a few rows at a time, so ones far larger than memory can be thumbnailed. Only
+// non-interlaced PNGs can be read this way, other formats are decoded whole.
+
+use crate::{E, R};
+use ::image::{DynamicImage, RgbaImage};
+use std::fs::File;
+use std::io::BufReader;
+
+pub struct Strips {
+    reader: png::Reader<BufReader<File>>,
+    width: u32,
+    height: u32,
+    color_type: png::ColorType,
+    rows_read: u32,
+}
+
+/// Opens the image at `path` for reading in strips, if its format allows.
+pub fn open(path: &str) -> Option<Strips> {
+    let file = File::open(path).ok()?;
+
+    // Palettes and low bit depths are expanded to 8 bits per sample.
+    let (info, reader) = png::Decoder::new(BufReader::new(file)).read_info().ok()?;
+    if reader.info().interlaced || info.bit_depth != png::BitDepth::Eight {
+        return None;
+    }
+
+    Some(Strips {
+        reader,
+        width: info.width,
+        height: info.height,
+        color_type: info.color_type,
+        rows_read: 0,
+    })
+}
+
+impl Strips {
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Whether the image has an alpha channel, whether or not it's used.
+    pub fn alpha(&self) -> bool {
+        matches!(
+            self.color_type,
+            png::ColorType::GrayscaleAlpha | png::ColorType::RGBA
+        )
+    }
+
+    /// Reads up to `rows` more rows, None once the whole image has been read.
+    pub fn read(&mut self, rows: u32) -> R<Option<DynamicImage>> {
+        let rows = std::cmp::min(rows, self.height - self.rows_read);
+        if rows == 0 {
+            return Ok(None);
+        }
+
+        let mut pixels = Vec::with_capacity((4 * self.width * rows) as usize);
+        for _ in 0..rows {
+            let row = self
+                .reader
+                .next_row()
+                .map_err(|e| E::ImageError(::image::ImageError::FormatError(format!("{}", e))))?
+                .ok_or_else(|| {
+                    E::ImageError(::image::ImageError::FormatError(String::from(
+                        "png truncated",
+                    )))
+                })?;
+
+            match self.color_type {
+                png::ColorType::Grayscale => {
+                    for &l in row {
+                        pixels.extend_from_slice(&[l, l, l, 255]);
+                    }
+                }
+                png::ColorType::GrayscaleAlpha => {
+                    for la in row.chunks_exact(2) {
+                        pixels.extend_from_slice(&[la[0], la[0], la[0], la[1]]);
+                    }
+                }
+                png::ColorType::RGB | png::ColorType::Indexed => {
+                    for rgb in row.chunks_exact(3) {
+                        pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+                    }
+                }
+                png::ColorType::RGBA => pixels.extend_from_slice(row),
+            }
+        }
+
+        self.rows_read += rows;
+
+        Ok(Some(DynamicImage::ImageRgba8(
+            RgbaImage::from_raw(self.width, rows, pixels).expect("strip size"),
+        )))
+    }
+}
+
+#[test]
+fn read() {
+    use ::image::GenericImageView;
+
+    let dir = crate::store::test_path("strips");
+    let path = dir.join("gray.png");
+    let mut gray = ::image::GrayImage::new(3, 5);
+    gray.put_pixel(2, 4, ::image::Luma([7]));
+    gray.save(&path).unwrap();
+
+    let mut strips = open(path.to_str().unwrap()).unwrap();
+    assert_eq!(strips.dimensions(), (3, 5));
+    assert!(!strips.alpha());
+
+    let first = strips.read(2).unwrap().unwrap();
+    assert_eq!(first.dimensions(), (3, 2));
+    let rest = strips.read(10).unwrap().unwrap();
+    assert_eq!(rest.dimensions(), (3, 3));
+    assert_eq!(rest.get_pixel(2, 2), ::image::Rgba([7, 7, 7, 255]));
+    assert!(strips.read(1).unwrap().is_none());
+
+    assert!(open("/nonexistent.png").is_none());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,144 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// SVG images. They have no pixel size, so every pyramid level is rendered at its own size rather
+// than downsampled from the largest.
+
+use crate::{E, R};
+use ::image::{DynamicImage, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use std::path::Path;
+use std::sync::Arc;
+
+static EXTENSIONS: &[&str] = &["svg", "svgz"];
+
+// The largest level is rendered at least this large, so small icons can be zoomed into, and at
+// most this large.
+static MIN_SIZE: u32 = 2048;
+static MAX_SIZE: u32 = 4096;
+
+lazy_static! {
+    // Loading system fonts is slow, so it's done once for all images.
+    static ref FONTS: Arc<usvg::fontdb::Database> = {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    };
+}
+
+pub fn is_svg(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+}
+
+pub struct Svg {
+    tree: usvg::Tree,
+}
+
+impl Svg {
+    pub fn open(path: &str) -> R<Self> {
+        let _s = crate::stats::ScopedDuration::new("svg::open");
+
+        let data = std::fs::read(path).map_err(E::IoError)?;
+
+        let options = usvg::Options {
+            // Relative references to raster images.
+            resources_dir: Path::new(path).parent().map(Path::to_path_buf),
+            fontdb: Arc::clone(&FONTS),
+            ..usvg::Options::default()
+        };
+
+        let tree =
+            usvg::Tree::from_data(&data, &options).map_err(|e| E::SvgError(format!("{}", e)))?;
+
+        Ok(Self { tree })
+    }
+
+    /// The size of the largest pyramid level, a power of two.
+    pub fn bucket(&self) -> u32 {
+        let size = self.tree.size();
+        let max = f32::max(size.width(), size.height()).ceil() as u32;
+        max.next_power_of_two().clamp(MIN_SIZE, MAX_SIZE)
+    }
+
+    /// Renders the image to fit in a `bucket` sized square.
+    pub fn render(&self, bucket: u32) -> R<DynamicImage> {
+        let _s = crate::stats::ScopedDuration::new("svg::render");
+
+        let size = self.tree.size();
+        let scale = bucket as f32 / f32::max(size.width(), size.height());
+        let w = ((size.width() * scale).round() as u32).max(1);
+        let h = ((size.height() * scale).round() as u32).max(1);
+
+        let mut pixmap = tiny_skia::Pixmap::new(w, h)
+            .ok_or_else(|| E::SvgError(format!("unable to render at {}x{}", w, h)))?;
+        resvg::render(
+            &self.tree,
+            tiny_skia::Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut(),
+        );
+
+        // Pixmaps are premultiplied.
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|p| {
+                let c = p.demultiply();
+                [c.red(), c.green(), c.blue(), c.alpha()]
+            })
+            .collect();
+
+        Ok(DynamicImage::ImageRgba8(
+            RgbaImage::from_raw(w, h, pixels).expect("pixmap size"),
+        ))
+    }
+}
+
+#[test]
+fn render() {
+    use ::image::GenericImageView;
+
+    assert!(is_svg("/a/b.SVG"));
+    assert!(!is_svg("/a/svg"));
+
+    let dir = crate::store::test_path("svg");
+    let path = dir.join("wide.svg");
+    std::fs::write(
+        &path,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20">
+            <rect width="20" height="20" fill="red"/>
+        </svg>"#,
+    )
+    .unwrap();
+
+    let svg = Svg::open(path.to_str().unwrap()).unwrap();
+    assert_eq!(svg.bucket(), MIN_SIZE);
+
+    // Each size is rendered, not scaled, so edges stay sharp.
+    for &bucket in &[8, 64] {
+        let image = svg.render(bucket).unwrap();
+        assert_eq!(image.dimensions(), (bucket, bucket / 2));
+        let edge = bucket / 2;
+        assert_eq!(
+            image.get_pixel(edge - 1, 0),
+            ::image::Rgba([255, 0, 0, 255])
+        );
+        assert_eq!(image.get_pixel(edge, 0), ::image::Rgba([0, 0, 0, 0]));
+    }
+
+    assert!(Svg::open("/nonexistent.svg").is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}
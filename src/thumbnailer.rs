@@ -12,8 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::database::Database;
+use crate::budget::Budget;
+use crate::codec::Codec;
+use crate::database::{Database, Failure};
 use crate::image;
+use crate::orientation::Orientation;
+use crate::strips::Strips;
 use crate::File;
 use crate::Metadata;
 use crate::TileMap;
@@ -21,187 +25,1612 @@ use crate::TileRef;
 use crate::R;
 use ::image::GenericImage;
 use ::image::GenericImageView;
-use futures::future::Fuse;
+use crossbeam_channel::{Receiver, Sender};
 use futures::future::FutureExt;
-use futures::future::RemoteHandle;
-use futures::select;
 use futures::task::SpawnExt;
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-
-type Handle<T> = Fuse<RemoteHandle<T>>;
+use std::time::{Duration, Instant};
 
 pub type MakeThumbRet = R<Metadata>;
 
+// Size animation frames are stored at.
+static ANIMATION_SIZE: u32 = 256;
+
+// Images with more pixels than this are tiled while they're read, where the format allows, down to
+// the first level no larger than `STRIP_MAX_BUCKET`.
+static STRIP_MIN_PIXELS: u64 = 1 << 26;
+static STRIP_MAX_BUCKET: u32 = 2048;
+
+// Decoded images are copied a couple of times (format conversion, orientation, ...).
+static DECODE_COPIES: u64 = 3;
+
+// Per pixel of the original, the resampler's intermediate rows of f32 samples. The first level
+// resampled from the original is half as tall.
+static RESAMPLE_BYTES: u64 = 8;
+
+// Levels are resampled from the level this many times their size, or the original if there's no
+// such level, rather than from the level just above so errors don't build up level by level.
+static RESAMPLE_SOURCE_RATIO: u32 = 4;
+
+// Memory reserved for images whose size isn't known before decoding (RAW, SVG, videos, ...).
+static UNKNOWN_MEMORY_ESTIMATE: u64 = 256 << 20;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub xdg: crate::xdg::Mode,
+
+    pub codec: Codec,
+
+    // Quality of lossy tiles for each pyramid level starting from the original size, the last
+    // entry applies to all smaller levels.
+    pub quality: Vec<u8>,
+
+    // Commands for files the image crate can't read, the first that handles a file is used.
+    pub external_decoders: Vec<crate::external::Decoder>,
+
+    // Memory concurrent jobs may use together, see `Budget`.
+    pub mem_limit: Option<u64>,
+
+    pub resampling: crate::resample::Resampling,
+
+    // Show shared thumbnails while the originals are decoded, see `Thumbnailer::preview`.
+    pub previews: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            xdg: crate::xdg::Mode::default(),
+            codec: Codec::default(),
+            quality: vec![100, 70],
+            external_decoders: vec![crate::external::Decoder::video()],
+            mem_limit: None,
+            resampling: crate::resample::Resampling::default(),
+            previews: true,
+        }
+    }
+}
+
+impl Options {
+    fn quality(&self, level: usize) -> u8 {
+        let last = self.quality.last().copied().unwrap_or(70);
+        self.quality.get(level).copied().unwrap_or(last)
+    }
+}
+
+// A thumbnail on its way through the pipeline. Decoding fills in the image's properties, resizing
+// makes the levels and encoding cuts them into tiles.
+struct Pyramid {
+    file: Arc<File>,
+    uid: u64,
+
+    svg: Option<crate::svg::Svg>,
+
+    // Made by `resize` and not tiled yet, largest first.
+    levels: Vec<(u32, ::image::DynamicImage)>,
+
+    frame_images: Vec<::image::DynamicImage>,
+    delays_ms: Vec<u32>,
+
+    // Levels tiled while the image was read, their tiles are already in the database.
+    thumbs: Vec<crate::Thumb>,
+
+    // The largest level left for `resize`.
+    bucket: u32,
+
+    orig_bucket: u32,
+    alpha: bool,
+    icc_converted: bool,
+    duration_ms: Option<u64>,
+}
+
+// The levels of an image tiled while it was read.
+struct Streamed {
+    thumbs: Vec<crate::Thumb>,
+
+    // The level after the streamed ones.
+    bucket: u32,
+
+    orig_bucket: u32,
+    alpha: bool,
+    icc_converted: bool,
+}
+
+// A pyramid level made a row at a time. Tiles are cut as soon as their rows are complete.
+struct StripLevel {
+    bucket: u32,
+    thumb: crate::Thumb,
+    spec: crate::TileSpec,
+    quality: u8,
+
+    // How the level below is made.
+    filter: crate::resample::Filter,
+    linear: bool,
+
+    // Rows of the tiles not cut yet.
+    band: Vec<u8>,
+    rows: u32,
+
+    // A row waiting for the next one to make a row of the level below.
+    pending: Option<Vec<u8>>,
+}
+
+impl StripLevel {
+    fn new(
+        bucket: u32,
+        img_size: [u32; 2],
+        format: crate::TileFormat,
+        alpha: bool,
+        quality: u8,
+        filter: crate::resample::Filter,
+        linear: bool,
+    ) -> Self {
+        let thumb = crate::Thumb {
+            img_size,
+            tile_refs: Vec::new(),
+            format,
+            alpha,
+        };
+        let spec = thumb.tile_spec();
+        Self {
+            bucket,
+            thumb,
+            spec,
+            quality,
+            filter,
+            linear,
+            band: Vec::new(),
+            rows: 0,
+            pending: None,
+        }
+    }
+
+    // Adds the next row, returning a row of the level below once it has one.
+    fn push(
+        &mut self,
+        row: Vec<u8>,
+        uid: u64,
+        codec: Codec,
+        tiles: &mut TileMap<Vec<u8>>,
+    ) -> R<Option<Vec<u8>>> {
+        let [w, h] = self.thumb.img_size;
+
+        self.band.extend_from_slice(&row);
+        self.rows += 1;
+
+        let band_rows = (self.band.len() / (4 * w as usize)) as u32;
+        if band_rows == self.spec.tile_size[1] || self.rows == h {
+            let band = ::image::RgbaImage::from_raw(w, band_rows, std::mem::take(&mut self.band))
+                .expect("band size");
+            for (min_x, max_x) in self.spec.x_ranges() {
+                let tile = band.view(min_x, 0, max_x - min_x, band_rows).to_image();
+                let buf = codec.encode(&tile, self.thumb.alpha, self.quality)?;
+                let chunk_id = self.thumb.tile_refs.len() as u16;
+                let tile_ref = TileRef::new(crate::Pow2::from(self.bucket), uid, chunk_id);
+                tiles.insert(tile_ref, buf);
+                self.thumb.tile_refs.push(tile_ref);
+            }
+        }
+
+        Ok(match self.pending.take() {
+            Some(prev) => Some(crate::resample::halve(
+                &prev,
+                &row,
+                self.filter,
+                self.linear,
+            )),
+            None if self.rows == h => {
+                Some(crate::resample::halve(&row, &row, self.filter, self.linear))
+            }
+            None => {
+                self.pending = Some(row);
+                None
+            }
+        })
+    }
+}
+
+/// Thumbnailing order: visible images first, then by distance from the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Priority {
+    pub hidden: bool,
+    pub mouse_dist: u64,
+}
+
+// Jobs waiting to start, per job that may run.
+static QUEUE_PER_THREAD: usize = 4;
+
+/// Time an update may take, the cache loader has what's left of it.
+pub static FRAME_BUDGET_MS: u64 = 10;
+
+// Frames in a row within the frame budget before another job may run in adaptive mode.
+static ADAPT_GOOD_FRAMES: usize = 30;
+
+/// Threads of each stage of the pipeline: reading and decoding images, resizing them into pyramid
+/// levels and encoding the levels' tiles.
+#[derive(Debug, Clone, Copy)]
+pub struct Threads {
+    pub decode: usize,
+    pub resize: usize,
+    pub encode: usize,
+
+    // Jobs in the pipeline at once.
+    pub jobs: usize,
+
+    // Run fewer jobs while frames take longer than their budget, see `Thumbnailer::adapt`.
+    pub adaptive: bool,
+}
+
+impl Threads {
+    /// `threads` decoders, and half as many threads for each of the other stages.
+    pub fn new(threads: usize) -> Self {
+        let threads = std::cmp::max(threads, 1);
+        let half = threads.div_ceil(2);
+        Self {
+            decode: threads,
+            resize: half,
+            encode: half,
+            jobs: threads + 2 * half,
+            adaptive: false,
+        }
+    }
+}
+
+// The thread pools of the stages after decoding, which runs on the job's own pool.
+struct Stages {
+    resize: futures::executor::ThreadPool,
+    encode: futures::executor::ThreadPool,
+}
+
+/// Where a job's time went.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+    // Waiting for a thread.
+    pub queued: Duration,
+
+    // Waiting for memory, see `Budget`.
+    pub reserving: Duration,
+
+    // Making and storing the thumbnails, the stages below included.
+    pub running: Duration,
+
+    // Each stage, including waiting for a thread of its pool.
+    pub decoding: Duration,
+    pub resizing: Duration,
+    pub encoding: Duration,
+}
+
+/// A finished job.
+pub struct Completion {
+    pub i: usize,
+    pub file: Arc<File>,
+    pub res: MakeThumbRet,
+    pub timing: Timing,
+
+    // Thumbnails made from a shared thumbnail, shown until the job finishes.
+    pub preview: bool,
+}
+
+// Sent by jobs as they finish.
+struct Done {
+    i: usize,
+    res: MakeThumbRet,
+    timing: Timing,
+    preview: bool,
+}
+
+struct Running {
+    file: Arc<File>,
+    priority: Priority,
+
+    // Set to stop the job at its next checkpoint, so a visible image can have its thread.
+    cancel: Arc<AtomicBool>,
+
+    // Removed from the database once the job finishes.
+    preview: Option<Metadata>,
+}
+
 pub struct Thumbnailer {
     db: Arc<Database>,
-    threads: usize,
-    uid_base: u64,
+    threads: Threads,
+    options: Arc<Options>,
+    budget: Arc<Budget>,
     executor: futures::executor::ThreadPool,
-    handles: BTreeMap<usize, Handle<MakeThumbRet>>,
+    stages: Arc<Stages>,
+
+    // Jobs allowed to run, lowered while frames are slow in adaptive mode.
+    limit: usize,
+    good_frames: usize,
+
+    // Jobs not started yet, best first. The sequence number keeps equal priorities in order.
+    queue: BTreeMap<(Priority, u64), (usize, Arc<File>, Instant)>,
+    queued: BTreeMap<usize, (Priority, u64)>,
+    next_seq: u64,
+
+    running: BTreeMap<usize, Running>,
+
+    done_tx: Sender<Done>,
+    done_rx: Receiver<Done>,
+
+    // Taken off the channel by `wait`.
+    ready: Vec<Done>,
 }
 
 impl Thumbnailer {
-    pub fn new(db: Arc<Database>, uid_base: u64, threads: usize) -> Self {
+    pub fn new(db: Arc<Database>, threads: Threads, options: Options) -> Self {
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+        let pool = |size: usize, name: &str| {
+            futures::executor::ThreadPool::builder()
+                .pool_size(std::cmp::max(size, 1))
+                .name_prefix(name)
+                .create()
+                .unwrap()
+        };
         Self {
             db,
             threads,
-            uid_base,
-            executor: futures::executor::ThreadPool::builder()
-                .pool_size(threads)
-                .name_prefix("thumbnailer")
-                .create()
-                .unwrap(),
-            handles: BTreeMap::new(),
+            budget: Budget::new(options.mem_limit),
+            options: Arc::new(options),
+            executor: pool(threads.decode, "thumbnailer"),
+            stages: Arc::new(Stages {
+                resize: pool(threads.resize, "resize"),
+                encode: pool(threads.encode, "encode"),
+            }),
+            limit: std::cmp::max(threads.jobs, 1),
+            good_frames: 0,
+            queue: BTreeMap::new(),
+            queued: BTreeMap::new(),
+            next_seq: 0,
+            running: BTreeMap::new(),
+            done_tx,
+            done_rx,
+            ready: Vec::new(),
         }
     }
 
     pub fn is_full(&self) -> bool {
-        self.handles.len() > self.threads
+        self.queue.len() >= self.limit * QUEUE_PER_THREAD
     }
 
-    pub fn contains(&self, i: usize) -> bool {
-        self.handles.contains_key(&i)
+    /// In adaptive mode, runs one job fewer after a `frame` over its time budget, and one more
+    /// again after a run of frames within it. Time the cache loader spent within the budget is
+    /// left out of `frame`, see `Stopwatch::elapsed_besides`.
+    pub fn adapt(&mut self, frame: Duration) {
+        if !self.threads.adaptive {
+            return;
+        }
+
+        let over_budget = frame >= Duration::from_millis(FRAME_BUDGET_MS);
+
+        if over_budget {
+            self.good_frames = 0;
+            if self.limit > 1 {
+                self.limit -= 1;
+                debug!("thumbnailer: backing off to {} jobs", self.limit);
+            }
+        } else {
+            self.good_frames += 1;
+            if self.good_frames >= ADAPT_GOOD_FRAMES && self.limit < self.threads.jobs {
+                self.good_frames = 0;
+                self.limit += 1;
+                debug!("thumbnailer: speeding up to {} jobs", self.limit);
+                self.start_jobs();
+            }
+        }
+
+        crate::stats::record(
+            "Thumbnailer::limit",
+            Duration::from_micros(self.limit as u64),
+        );
     }
 
-    async fn update_db(
-        res: R<(Arc<File>, Metadata, TileMap<Vec<u8>>)>,
-        db: Arc<Database>,
-    ) -> R<Metadata> {
-        match res {
-            Ok((file, metadata, tiles)) => {
-                // Do before metadata write to prevent invalid metadata references.
-                for (id, tile) in tiles {
-                    db.set(id, &tile).expect("db set");
-                }
+    /// Drops the jobs that haven't started, e.g. when the view moved and they are to be queued
+    /// again in a new order.
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+        self.queued.clear();
+    }
+
+    fn update_db(
+        file: &File,
+        metadata: Metadata,
+        tiles: TileMap<Vec<u8>>,
+        db: &Database,
+    ) -> MakeThumbRet {
+        // Do before metadata write to prevent invalid metadata references.
+        for (id, tile) in tiles {
+            db.set(id, &tile)?;
+        }
 
-                db.set_metadata(&*file, &metadata).expect("set metadata");
+        db.set_metadata(file, &metadata)?;
 
-                Ok(metadata)
+        Ok(metadata)
+    }
+
+    /// Whether jobs are running.
+    pub fn is_busy(&self) -> bool {
+        !self.running.is_empty()
+    }
+
+    /// Whether jobs have finished since the last `recv`.
+    pub fn has_completions(&self) -> bool {
+        !self.ready.is_empty() || !self.done_rx.is_empty()
+    }
+
+    /// Blocks until a job finishes or `timeout` passes.
+    pub fn wait(&mut self, timeout: Duration) {
+        if self.ready.is_empty() {
+            if let Ok(done) = self.done_rx.recv_timeout(timeout) {
+                self.ready.push(done);
             }
-            Err(e) => Err(e),
         }
     }
 
-    pub fn recv(&mut self) -> Vec<(usize, R<Metadata>)> {
+    /// The jobs that finished since the last call.
+    pub fn recv(&mut self) -> Vec<Completion> {
         let mut ret = Vec::new();
 
-        // TODO: make more efficient
-        for (&i, mut handle) in &mut self.handles {
-            select! {
-                thumb_res = handle => {
-                    ret.push((i, thumb_res));
-                }
-                default => {}
+        let mut done = std::mem::take(&mut self.ready);
+        done.extend(self.done_rx.try_iter());
+
+        for Done {
+            i,
+            res,
+            timing,
+            preview,
+        } in done
+        {
+            if preview {
+                let running = self.running.get_mut(&i).unwrap();
+                running.preview = res.as_ref().ok().cloned();
+                ret.push(Completion {
+                    i,
+                    file: Arc::clone(&running.file),
+                    res,
+                    timing,
+                    preview,
+                });
+                continue;
             }
-        }
 
-        for (i, _) in &ret {
-            self.handles.remove(i);
+            let running = self.running.remove(&i).unwrap();
+
+            // Its thumbnails replace the preview.
+            if let Some(preview) = &running.preview {
+                self.discard(preview);
+            }
+
+            // Preempted jobs start over later.
+            if let Err(crate::E::Cancelled) = res {
+                self.enqueue(i, running.file, running.priority);
+                continue;
+            }
+
+            crate::stats::record("Thumbnailer::queued", timing.queued);
+            crate::stats::record("Thumbnailer::running", timing.running);
+
+            ret.push(Completion {
+                i,
+                file: running.file,
+                res,
+                timing,
+                preview,
+            });
         }
 
+        self.start_jobs();
+
         ret
     }
 
-    pub fn make_thumbs(&mut self, image: &image::Image) -> bool {
-        assert!(!self.is_full());
+    // Removes the tiles of a preview and frees its id.
+    fn discard(&self, preview: &Metadata) {
+        let res = preview
+            .tile_refs()
+            .try_for_each(|tile_ref| self.db.remove(*tile_ref))
+            .and_then(|()| preview.index().map_or(Ok(()), |id| self.db.release(id)));
+        if let Err(e) = res {
+            error!("discard preview: {:?}", e);
+        }
+    }
 
-        if !image.is_missing() || self.contains(image.i) {
+    /// Queues thumbnailing `image`, or moves it in the queue if it's already there.
+    pub fn make_thumbs(&mut self, image: &image::Image, priority: Priority) -> bool {
+        if !image.is_missing() || self.running.contains_key(&image.i) {
             return false;
         }
 
-        let uid = self.uid_base + image.i as u64;
+        self.enqueue(image.i, Arc::clone(&image.file), priority);
 
+        self.start_jobs();
+
+        true
+    }
+
+    fn enqueue(&mut self, i: usize, file: Arc<File>, priority: Priority) {
+        if let Some(key) = self.queued.remove(&i) {
+            self.queue.remove(&key);
+        }
+
+        let key = (priority, self.next_seq);
+        self.next_seq += 1;
+
+        self.queue.insert(key, (i, file, Instant::now()));
+        self.queued.insert(i, key);
+    }
+
+    // Starts the best jobs while fewer than the limit run. Jobs of hidden images are cancelled for
+    // visible ones waiting.
+    fn start_jobs(&mut self) {
+        while self.running.len() < self.limit {
+            let (key, (i, file, queued_at)) = match self.queue.iter().next() {
+                Some((&key, _)) => (key, self.queue.remove(&key).unwrap()),
+                None => return,
+            };
+            self.queued.remove(&i);
+            self.spawn(i, file, key.0, queued_at.elapsed());
+        }
+
+        let visible_waiting = self
+            .queue
+            .keys()
+            .take_while(|(priority, _)| !priority.hidden)
+            .count();
+
+        let mut preemptible: Vec<&Running> = self
+            .running
+            .values()
+            .filter(|running| running.priority.hidden && !running.cancel.load(Ordering::Relaxed))
+            .collect();
+
+        // The furthest go first.
+        preemptible.sort_by_key(|running| std::cmp::Reverse(running.priority));
+
+        for running in preemptible.into_iter().take(visible_waiting) {
+            running.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn spawn(&mut self, i: usize, file: Arc<File>, priority: Priority, queued: Duration) {
         let db = Arc::clone(&self.db);
 
-        let fut =
-            Self::make_thumb(Arc::clone(&image.file), uid).then(move |r| Self::update_db(r, db));
+        let options = Arc::clone(&self.options);
 
-        let handle = self.executor.spawn_with_handle(fut).unwrap().fuse();
+        let stages = Arc::clone(&self.stages);
 
-        self.handles.insert(image.i, handle);
+        let budget = Arc::clone(&self.budget);
 
-        true
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let done_tx = self.done_tx.clone();
+
+        let fut = {
+            let file = Arc::clone(&file);
+            let cancel = Arc::clone(&cancel);
+            async move {
+                let mut timing = Timing {
+                    queued,
+                    ..Timing::default()
+                };
+
+                // Jobs wait their turn for memory here, before taking an id.
+                let start = Instant::now();
+                let _memory = budget
+                    .reserve(|| Self::memory_estimate(&file.path, options.codec))
+                    .await;
+                timing.reserving = start.elapsed();
+
+                let start = Instant::now();
+                let res = Self::check_cancel(&cancel);
+                let res = match res {
+                    Ok(()) => {
+                        let send_preview = |metadata| {
+                            let _ = done_tx.send(Done {
+                                i,
+                                res: Ok(metadata),
+                                timing: Timing::default(),
+                                preview: true,
+                            });
+                        };
+                        let job = Self::run_job(
+                            file,
+                            db,
+                            options,
+                            &stages,
+                            &cancel,
+                            &mut timing,
+                            &send_preview,
+                        );
+                        std::panic::AssertUnwindSafe(job)
+                            .catch_unwind()
+                            .await
+                            .unwrap_or_else(Self::panicked)
+                    }
+                    Err(e) => Err(e),
+                };
+                timing.running = start.elapsed();
+
+                // The receiver is gone once the thumbnailer is.
+                let _ = done_tx.send(Done {
+                    i,
+                    res,
+                    timing,
+                    preview: false,
+                });
+            }
+        };
+
+        self.executor.spawn(fut).unwrap();
+
+        self.running.insert(
+            i,
+            Running {
+                file,
+                priority,
+                cancel,
+                preview: None,
+            },
+        );
     }
 
-    async fn make_thumb(file: Arc<File>, uid: u64) -> R<(Arc<File>, Metadata, TileMap<Vec<u8>>)> {
-        let _s = crate::stats::ScopedDuration::new("Thumbnailer::make_thumb");
+    fn panicked<T>(panic: Box<dyn std::any::Any + Send>) -> R<T> {
+        let msg = panic
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(crate::E::Panicked(msg))
+    }
+
+    // Runs a stage of the pipeline on `pool`, leaving the job's thread free meanwhile.
+    async fn on<T, F>(pool: &futures::executor::ThreadPool, f: F) -> R<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> R<T> + Send + 'static,
+    {
+        let handle = pool
+            .spawn_with_handle(async move {
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+                    .unwrap_or_else(Self::panicked)
+            })
+            .map_err(|e| crate::E::Panicked(format!("spawn: {}", e)))?;
+        handle.await
+    }
+
+    async fn run_job(
+        file: Arc<File>,
+        db: Arc<Database>,
+        options: Arc<Options>,
+        stages: &Stages,
+        cancel: &Arc<AtomicBool>,
+        timing: &mut Timing,
+        send_preview: &(dyn Fn(Metadata) + Sync),
+    ) -> MakeThumbRet {
+        // A job that has shown a preview finishes, so the preview is always replaced.
+        let uncancelled = Arc::new(AtomicBool::new(false));
+        let cancel = if options.previews && options.xdg.reads() {
+            match Self::preview(&file, &db, &options) {
+                Some(metadata) => {
+                    send_preview(metadata);
+                    &uncancelled
+                }
+                None => cancel,
+            }
+        } else {
+            cancel
+        };
+
+        // Reserved lazily so only images that actually get thumbnailed consume an id.
+        let uid = db.reserve()?;
+
+        let mut streamed_tiles = Vec::new();
+        let job = Self::make_thumb(
+            Arc::clone(&file),
+            uid,
+            options,
+            stages,
+            cancel,
+            timing,
+            &db,
+            &mut streamed_tiles,
+        );
+        // Caught here too so the id of a panicked job is released.
+        let res = std::panic::AssertUnwindSafe(job)
+            .catch_unwind()
+            .await
+            .unwrap_or_else(Self::panicked);
+        let res = match res {
+            // Tiles of a failed update stay reserved under the id until a later session's GC.
+            Ok((file, metadata, tiles)) => Self::update_db(&file, metadata, tiles, &db),
+            Err(e) => {
+                // Once nothing is written under the id it can be reused straight away.
+                let removed = streamed_tiles
+                    .into_iter()
+                    .try_for_each(|tile_ref| db.remove(tile_ref))
+                    .and_then(|()| db.release(uid));
+                if let Err(e) = removed {
+                    error!("release {}: {:?}", uid, e);
+                }
+                Err(e)
+            }
+        };
+
+        // Files that can't be thumbnailed are skipped next time, until they change.
+        if let Some(failure) = res.as_ref().err().and_then(Failure::of) {
+            if let Err(e) = db.set_failure(&file, &failure) {
+                error!("set_failure {}: {:?}", file.path, e);
+            }
+        }
 
-        let mut image = ::image::open(&file.path).map_err(crate::E::ImageError)?;
+        res
+    }
+
+    // Makes the levels a shared thumbnail of `file` covers, stored under an id of their own, so
+    // they can be shown before the original is decoded.
+    fn preview(file: &Arc<File>, db: &Database, options: &Options) -> Option<Metadata> {
+        let _s = crate::stats::ScopedDuration::new("Thumbnailer::preview");
+
+        let (_, seed) = crate::xdg::lookup(file)?;
+
+        let uid = match db.reserve() {
+            Ok(uid) => uid,
+            Err(e) => {
+                error!("preview {}: {:?}", file.path, e);
+                return None;
+            }
+        };
+
+        let (w, h) = seed.dimensions();
+        let bucket = std::cmp::max(w, h).next_power_of_two();
+        let pyramid = Pyramid {
+            file: Arc::clone(file),
+            uid,
+            svg: None,
+            levels: Vec::new(),
+            frame_images: Vec::new(),
+            delays_ms: Vec::new(),
+            thumbs: Vec::new(),
+            bucket,
+            orig_bucket: bucket,
+            alpha: Self::has_alpha(&seed),
+            // Shared thumbnails are in sRGB already.
+            icc_converted: false,
+            duration_ms: None,
+        };
+
+        let never = AtomicBool::new(false);
+        let (_, metadata, tiles) = match Self::resize(pyramid, seed, options, &never)
+            .and_then(|pyramid| Self::encode(pyramid, options, &never))
+        {
+            Ok(ret) => ret,
+            Err(e) => {
+                error!("preview {}: {:?}", file.path, e);
+                let _ = db.release(uid);
+                return None;
+            }
+        };
+
+        let written = tiles
+            .iter()
+            .try_for_each(|(tile_ref, tile)| db.set(*tile_ref, tile));
+        if let Err(e) = written {
+            error!("preview {}: {:?}", file.path, e);
+            for tile_ref in tiles.keys() {
+                let _ = db.remove(*tile_ref);
+            }
+            let _ = db.release(uid);
+            return None;
+        }
 
+        Some(metadata)
+    }
+
+    fn check_cancel(cancel: &AtomicBool) -> R<()> {
+        if cancel.load(Ordering::Relaxed) {
+            Err(crate::E::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Only images that actually use their alpha channel get the larger alpha capable tiles.
+    fn has_alpha(image: &::image::DynamicImage) -> bool {
+        match image {
+            ::image::DynamicImage::ImageLumaA8(image) => image.pixels().any(|p| p[1] != 255),
+            ::image::DynamicImage::ImageRgba8(image) => image.pixels().any(|p| p[3] != 255),
+            ::image::DynamicImage::ImageBgra8(image) => image.pixels().any(|p| p[3] != 255),
+            _ => false,
+        }
+    }
+
+    // Cuts `image` into the tiles of a thumbnail.
+    fn make_tiles(
+        image: &mut ::image::DynamicImage,
+        format: crate::TileFormat,
+        alpha: bool,
+        quality: u8,
+        options: &Options,
+        next_ref: &mut dyn FnMut() -> TileRef,
+        tiles: &mut TileMap<Vec<u8>>,
+    ) -> R<crate::Thumb> {
         let (w, h) = image.dimensions();
 
-        let orig_bucket = std::cmp::max(w, h).next_power_of_two();
+        let mut thumb = crate::Thumb {
+            img_size: [w, h],
+            tile_refs: Vec::new(),
+            format,
+            alpha,
+        };
+
+        let spec = thumb.tile_spec();
+
+        for (min_y, max_y) in spec.y_ranges() {
+            let y_range = max_y - min_y;
+
+            for (min_x, max_x) in spec.x_ranges() {
+                let x_range = max_x - min_x;
+
+                let sub_image = image.sub_image(min_x, min_y, x_range, y_range).to_image();
+
+                let buf = options.codec.encode(&sub_image, alpha, quality)?;
+
+                let tile_id = next_ref();
+
+                tiles.insert(tile_id, buf);
+
+                thumb.tile_refs.push(tile_id);
+            }
+        }
+
+        Ok(thumb)
+    }
+
+    // Opens `path` for reading in strips if it's large enough to need it.
+    fn strips(path: &str) -> Option<Strips> {
+        crate::strips::open(path).filter(|strips| {
+            let (w, h) = strips.dimensions();
+            u64::from(w) * u64::from(h) > STRIP_MIN_PIXELS
+                && std::cmp::max(w, h).next_power_of_two() > STRIP_MAX_BUCKET
+        })
+    }
+
+    // Roughly the most memory thumbnailing the image at `path` takes.
+    fn memory_estimate(path: &str, codec: Codec) -> u64 {
+        if let Some(strips) = Self::strips(path) {
+            let (w, h) = strips.dimensions();
+            let level = crate::Thumb {
+                img_size: [w, h],
+                tile_refs: Vec::new(),
+                format: crate::TileFormat::Raw,
+                alpha: false,
+            };
+            // A band of tiles and a pending row for each level, the levels below halve in size.
+            let band_pixels = u64::from(level.tile_spec().tile_size[1] + 1) * u64::from(w);
+            let whole = u64::from(STRIP_MAX_BUCKET).pow(2) * 4 * DECODE_COPIES;
+            // The encoded tiles of a band of each level are held until they're written.
+            let encoded = 2 * band_pixels * codec.bytes_per_pixel(strips.alpha());
+            return 2 * band_pixels * 4 + whole + encoded;
+        }
+
+        match ::image::image_dimensions(path) {
+            Ok((w, h)) => u64::from(w) * u64::from(h) * (4 * DECODE_COPIES + RESAMPLE_BYTES),
+            Err(_) => UNKNOWN_MEMORY_ESTIMATE,
+        }
+    }
+
+    // Tiles the levels of `strips` larger than `max_bucket` as rows are read, returning them and
+    // the next level down. Each band's tiles are written to `db` and added to `written` before the
+    // next band is read. Levels are halved two rows at a time, see `resample::halve`.
+    #[allow(clippy::too_many_arguments)]
+    fn make_strip_levels(
+        strips: &mut Strips,
+        uid: u64,
+        profile: Option<&[u8]>,
+        options: &Options,
+        resampling: &crate::resample::Resampling,
+        max_bucket: u32,
+        cancel: &AtomicBool,
+        db: &Database,
+        written: &mut Vec<TileRef>,
+    ) -> R<(Streamed, ::image::DynamicImage)> {
+        let _s = crate::stats::ScopedDuration::new("Thumbnailer::make_strip_levels");
 
-        let min_bucket = std::cmp::min(8, orig_bucket);
+        let (w, h) = strips.dimensions();
+        let alpha = strips.alpha();
+        let format = options.codec.format(alpha);
+        let orig_bucket = std::cmp::max(w, h).next_power_of_two();
 
+        let mut levels: Vec<StripLevel> = Vec::new();
+        let mut img_size = [w, h];
         let mut bucket = orig_bucket;
+        while bucket > max_bucket {
+            let quality = options.quality(levels.len());
+            let filter = resampling.filter(levels.len() + 1);
+            levels.push(StripLevel::new(
+                bucket,
+                img_size,
+                format,
+                alpha,
+                quality,
+                filter,
+                resampling.linear,
+            ));
+            img_size = [img_size[0].div_ceil(2), img_size[1].div_ceil(2)];
+            bucket >>= 1;
+        }
+
+        let mut last = Vec::with_capacity((4 * img_size[0] * img_size[1]) as usize);
+        let mut tiles = TileMap::new();
+        let mut icc_converted = false;
 
-        let mut thumbs: Vec<crate::Thumb> = Vec::new();
+        let band_rows = levels.first().map_or(h, |level| level.spec.tile_size[1]);
+        while let Some(mut strip) = strips.read(band_rows)? {
+            Self::check_cancel(cancel)?;
 
-        let mut tiles: BTreeMap<TileRef, Vec<u8>> = BTreeMap::new();
+            if let Some(profile) = profile {
+                icc_converted = crate::icc::to_srgb(&mut strip, profile);
+            }
+            for row in strip.to_rgba().chunks_exact(4 * w as usize) {
+                Self::push_strip_row(
+                    &mut levels,
+                    &mut last,
+                    row.to_vec(),
+                    uid,
+                    options,
+                    &mut tiles,
+                )?;
+            }
+
+            for (tile_ref, tile) in std::mem::take(&mut tiles) {
+                written.push(tile_ref);
+                db.set(tile_ref, &tile)?;
+            }
+        }
+
+        let image = ::image::RgbaImage::from_raw(img_size[0], img_size[1], last)
+            .ok_or_else(|| crate::E::CorruptData(String::from("strips")))?;
+
+        let streamed = Streamed {
+            thumbs: levels.into_iter().map(|level| level.thumb).collect(),
+            bucket,
+            orig_bucket,
+            alpha,
+            icc_converted,
+        };
+
+        Ok((streamed, ::image::DynamicImage::ImageRgba8(image)))
+    }
+
+    // Passes a row down the levels, the rows of the first level not streamed are kept in `last`.
+    fn push_strip_row(
+        levels: &mut [StripLevel],
+        last: &mut Vec<u8>,
+        row: Vec<u8>,
+        uid: u64,
+        options: &Options,
+        tiles: &mut TileMap<Vec<u8>>,
+    ) -> R<()> {
+        match levels.split_first_mut() {
+            Some((level, rest)) => {
+                if let Some(next) = level.push(row, uid, options.codec, tiles)? {
+                    Self::push_strip_row(rest, last, next, uid, options, tiles)?;
+                }
+            }
+            None => last.extend_from_slice(&row),
+        }
+        Ok(())
+    }
+
+    // Makes the thumbnails of `file` through the stages of the pipeline. The tiles of levels made
+    // while the image is read are written straight away and listed in `streamed_tiles`.
+    #[allow(clippy::too_many_arguments)]
+    async fn make_thumb(
+        file: Arc<File>,
+        uid: u64,
+        options: Arc<Options>,
+        stages: &Stages,
+        cancel: &Arc<AtomicBool>,
+        timing: &mut Timing,
+        db: &Database,
+        streamed_tiles: &mut Vec<TileRef>,
+    ) -> R<(Arc<File>, Metadata, TileMap<Vec<u8>>)> {
+        let _s = crate::stats::ScopedDuration::new("Thumbnailer::make_thumb");
+
+        let start = Instant::now();
+        let (pyramid, image) = Self::decode(file, uid, &options, cancel, db, streamed_tiles)?;
+        timing.decoding = start.elapsed();
+
+        let start = Instant::now();
+        let pyramid = {
+            let options = Arc::clone(&options);
+            let cancel = Arc::clone(cancel);
+            Self::on(&stages.resize, move || {
+                let pyramid = Self::resize(pyramid, image, &options, &cancel)?;
+                if options.xdg.writes() {
+                    Self::publish(&pyramid);
+                }
+                Ok(pyramid)
+            })
+            .await?
+        };
+        timing.resizing = start.elapsed();
+
+        let start = Instant::now();
+        let ret = {
+            let cancel = Arc::clone(cancel);
+            Self::on(&stages.encode, move || {
+                Self::encode(pyramid, &options, &cancel)
+            })
+            .await?
+        };
+        timing.encoding = start.elapsed();
+
+        Ok(ret)
+    }
+
+    // Reads the image, returning the largest level still to be made from it.
+    fn decode(
+        file: Arc<File>,
+        uid: u64,
+        options: &Options,
+        cancel: &AtomicBool,
+        db: &Database,
+        streamed_tiles: &mut Vec<TileRef>,
+    ) -> R<(Pyramid, ::image::DynamicImage)> {
+        let _s = crate::stats::ScopedDuration::new("Thumbnailer::decode");
+
+        let external = crate::external::find(&options.external_decoders, &file.path);
+
+        let svg = if external.is_none() && crate::svg::is_svg(&file.path) {
+            Some(crate::svg::Svg::open(&file.path)?)
+        } else {
+            None
+        };
+
+        // The first frame of an animation is its pyramid.
+        let mut frames = if external.is_some() || svg.is_some() {
+            Vec::new()
+        } else {
+            match crate::animation::decode(&file.path, ANIMATION_SIZE) {
+                Ok(frames) => frames.filter(|frames| frames.len() > 1).unwrap_or_default(),
+                // A broken frame further in doesn't cost the image its thumbnail.
+                Err(e) => {
+                    info!("{}: {}, thumbnailing it as a still image", file.path, e);
+                    Vec::new()
+                }
+            }
+        };
+
+        let orientation = Orientation::read(&file.path);
+
+        // Nothing to convert in sRGB images.
+        let profile =
+            crate::icc::profile(&file.path).filter(|profile| !crate::icc::is_srgb(profile));
+
+        // Very large images are tiled as they're read, the rest of the pyramid is made as usual.
+        let mut strips = if external.is_none()
+            && svg.is_none()
+            && frames.is_empty()
+            && orientation.is_identity()
+        {
+            Self::strips(&file.path)
+        } else {
+            None
+        };
+
+        let mut streamed = None;
+
+        let mut duration_ms = None;
+
+        let read = if let Some(strips) = strips.as_mut() {
+            Self::make_strip_levels(
+                strips,
+                uid,
+                profile.as_deref(),
+                options,
+                &options.resampling.for_path(&file.path),
+                STRIP_MAX_BUCKET,
+                cancel,
+                db,
+                streamed_tiles,
+            )
+            .map(|(levels, image)| {
+                streamed = Some(levels);
+                image
+            })
+        } else if let Some(external) = external {
+            external.decode(&file.path).map(|decoded| {
+                duration_ms = decoded.duration_ms;
+                decoded.image
+            })
+        } else if let Some(svg) = &svg {
+            svg.render(svg.bucket())
+        } else if !frames.is_empty() {
+            Ok(frames[0].image.clone())
+        } else if crate::raw::is_raw(&file.path) {
+            crate::raw::open(&file.path)
+        } else {
+            ::image::open(&file.path).map_err(crate::E::ImageError)
+        };
+
+        let (mut image, orientation, profile) = match read {
+            Ok(image) => (image, orientation, profile),
+            // Another application may have thumbnailed a file pix can't read, that thumbnail is
+            // the best there is. It's already oriented and in sRGB.
+            Err(e) if Failure::of(&e).is_some() && options.xdg.reads() => {
+                match crate::xdg::lookup(&file) {
+                    Some((_, seed)) => {
+                        info!("{}: {}, using its shared thumbnail", file.path, e);
+                        (seed, Orientation::default(), None)
+                    }
+                    None => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Tile in display orientation so sizes and the tile grid match what is shown.
+        image = orientation.apply(image);
+
+        let icc_converted = match &streamed {
+            Some(streamed) => streamed.icc_converted,
+            None => profile
+                .as_ref()
+                .is_some_and(|profile| crate::icc::to_srgb(&mut image, profile)),
+        };
+
+        let delays_ms: Vec<u32> = frames.iter().map(|frame| frame.delay_ms).collect();
+        let frame_images: Vec<::image::DynamicImage> = frames
+            .drain(..)
+            .skip(1)
+            .map(|frame| {
+                let mut frame_image = orientation.apply(frame.image);
+                if icc_converted {
+                    crate::icc::to_srgb(&mut frame_image, profile.as_ref().unwrap());
+                }
+                frame_image
+            })
+            .collect();
+
+        let (w, h) = image.dimensions();
+
+        let alpha = match &streamed {
+            Some(streamed) => streamed.alpha,
+            None => Self::has_alpha(&image) || frame_images.iter().any(Self::has_alpha),
+        };
+
+        let orig_bucket = match &streamed {
+            Some(streamed) => streamed.orig_bucket,
+            None => std::cmp::max(w, h).next_power_of_two(),
+        };
+
+        let (thumbs, bucket) = match streamed {
+            Some(streamed) => (streamed.thumbs, streamed.bucket),
+            None => (Vec::new(), orig_bucket),
+        };
+
+        let pyramid = Pyramid {
+            file,
+            uid,
+            svg,
+            levels: Vec::new(),
+            frame_images,
+            delays_ms,
+            thumbs,
+            bucket,
+            orig_bucket,
+            alpha,
+            icc_converted,
+            duration_ms,
+        };
+
+        Ok((pyramid, image))
+    }
+
+    // Makes the levels of the pyramid from `image` down to the smallest.
+    fn resize(
+        mut pyramid: Pyramid,
+        image: ::image::DynamicImage,
+        options: &Options,
+        cancel: &AtomicBool,
+    ) -> R<Pyramid> {
+        let _s = crate::stats::ScopedDuration::new("Thumbnailer::resize");
+
+        let resampling = options.resampling.for_path(&pyramid.file.path);
+
+        let min_bucket = std::cmp::min(8, pyramid.orig_bucket);
+
+        let mut bucket = pyramid.bucket;
 
         while min_bucket <= bucket {
+            Self::check_cancel(cancel)?;
+
             let current_bucket = {
                 let (w, h) = image.dimensions();
                 std::cmp::max(w, h).next_power_of_two()
             };
 
             // Downsample if needed.
-            if bucket < current_bucket {
-                image = image.thumbnail(bucket, bucket);
-            }
+            let level = if let Some(svg) = pyramid.svg.as_ref().filter(|_| bucket < current_bucket)
+            {
+                svg.render(bucket)?
+            } else if bucket < current_bucket {
+                let source = pyramid
+                    .levels
+                    .iter()
+                    .rev()
+                    .find(|(level_bucket, _)| *level_bucket >= RESAMPLE_SOURCE_RATIO * bucket)
+                    .map_or(&image, |(_, level)| level);
+                let filter = resampling.filter(pyramid.thumbs.len() + pyramid.levels.len());
+                crate::resample::resample(source, bucket, filter, resampling.linear)
+            } else {
+                image.clone()
+            };
 
-            let lossy = bucket != orig_bucket;
+            pyramid.levels.push((bucket, level));
 
-            let (w, h) = image.dimensions();
+            bucket >>= 1;
+        }
 
-            let mut chunk_id = 0u16;
+        Ok(pyramid)
+    }
 
-            let mut thumb = crate::Thumb {
-                img_size: [w, h],
-                tile_refs: Vec::new(),
-            };
+    // Writes the levels other programs use to the shared thumbnail cache.
+    fn publish(pyramid: &Pyramid) {
+        let levels: BTreeMap<u32, ::image::DynamicImage> = pyramid
+            .levels
+            .iter()
+            .filter(|(bucket, _)| (128..=1024).contains(bucket))
+            .cloned()
+            .collect();
+        if !levels.is_empty() {
+            crate::xdg::publish(&pyramid.file, &levels);
+        }
+    }
 
-            let spec = thumb.tile_spec();
+    // Cuts the levels into tiles.
+    fn encode(
+        pyramid: Pyramid,
+        options: &Options,
+        cancel: &AtomicBool,
+    ) -> R<(Arc<File>, Metadata, TileMap<Vec<u8>>)> {
+        let _s = crate::stats::ScopedDuration::new("Thumbnailer::encode");
 
-            for (min_y, max_y) in spec.y_ranges() {
-                let y_range = max_y - min_y;
+        let Pyramid {
+            file,
+            uid,
+            levels,
+            frame_images,
+            delays_ms,
+            mut thumbs,
+            orig_bucket,
+            alpha,
+            icc_converted,
+            duration_ms,
+            ..
+        } = pyramid;
 
-                for (min_x, max_x) in spec.x_ranges() {
-                    let x_range = max_x - min_x;
+        let format = options.codec.format(alpha);
 
-                    let sub_image = ::image::DynamicImage::ImageRgba8(
-                        image.sub_image(min_x, min_y, x_range, y_range).to_image(),
-                    );
+        let mut tiles = TileMap::new();
 
-                    let format = if lossy {
-                        ::image::ImageOutputFormat::JPEG(70)
-                    } else {
-                        ::image::ImageOutputFormat::JPEG(100)
-                    };
+        // Animation frames are only stored at one size.
+        let frame_bucket = std::cmp::min(orig_bucket, ANIMATION_SIZE);
 
-                    let mut buf = Vec::with_capacity((2 * x_range * y_range) as usize);
-                    sub_image.write_to(&mut buf, format).expect("write_to");
+        let mut frame_thumbs: Vec<crate::Thumb> = Vec::new();
 
-                    let tile_id = crate::TileRef::new(crate::Pow2::from(bucket), uid, chunk_id);
-                    chunk_id += 1;
+        for (bucket, mut image) in levels {
+            Self::check_cancel(cancel)?;
 
-                    tiles.insert(tile_id, buf);
+            let quality = options.quality(thumbs.len());
 
-                    thumb.tile_refs.push(tile_id);
-                }
-            }
+            let mut chunk_id = 0u16;
+            let mut next_ref = || {
+                let tile_ref = crate::TileRef::new(crate::Pow2::from(bucket), uid, chunk_id);
+                chunk_id += 1;
+                tile_ref
+            };
 
+            let thumb = Self::make_tiles(
+                &mut image,
+                format,
+                alpha,
+                quality,
+                options,
+                &mut next_ref,
+                &mut tiles,
+            )?;
             thumbs.push(thumb);
 
-            bucket >>= 1;
+            // Frame tiles follow the level's tiles.
+            if bucket == frame_bucket {
+                for frame_image in &frame_images {
+                    let mut frame_image = frame_image.clone();
+                    let thumb = Self::make_tiles(
+                        &mut frame_image,
+                        format,
+                        alpha,
+                        quality,
+                        options,
+                        &mut next_ref,
+                        &mut tiles,
+                    )?;
+                    frame_thumbs.push(thumb);
+                }
+            }
         }
 
         thumbs.reverse();
 
-        let metadata = Metadata { thumbs };
+        let animation = if frame_thumbs.is_empty() {
+            None
+        } else {
+            Some(crate::Animation {
+                delays_ms,
+                frames: frame_thumbs,
+            })
+        };
+
+        let metadata = Metadata {
+            thumbs,
+            icc_converted,
+            animation,
+            duration_ms,
+        };
 
         Ok((file, metadata, tiles))
     }
 }
+
+#[test]
+fn strip_levels() {
+    let dir = crate::store::test_path("strip_levels");
+    let path = dir.join("large.png");
+    let mut original = ::image::RgbImage::new(300, 200);
+    original.put_pixel(299, 199, ::image::Rgb([255, 0, 0]));
+    original.save(&path).unwrap();
+
+    let options = Options {
+        codec: Codec::Raw,
+        ..Options::default()
+    };
+    let db = Database::open(crate::store::Backend::Memory, "").unwrap();
+    let mut written = Vec::new();
+    let mut strips = crate::strips::open(path.to_str().unwrap()).unwrap();
+    let (streamed, image) = Thumbnailer::make_strip_levels(
+        &mut strips,
+        1,
+        None,
+        &options,
+        &options.resampling,
+        64,
+        &AtomicBool::new(false),
+        &db,
+        &mut written,
+    )
+    .unwrap();
+
+    let sizes: Vec<[u32; 2]> = streamed.thumbs.iter().map(|t| t.img_size).collect();
+    assert_eq!(sizes, vec![[300, 200], [150, 100], [75, 50]]);
+    assert_eq!(streamed.bucket, 64);
+    assert_eq!(streamed.orig_bucket, 512);
+
+    for thumb in &streamed.thumbs {
+        let spec = thumb.tile_spec();
+        assert_eq!(
+            thumb.tile_refs.len(),
+            (spec.grid_size[0] * spec.grid_size[1]) as usize
+        );
+        assert!(thumb
+            .tile_refs
+            .iter()
+            .all(|t| written.contains(t) && db.get(*t).unwrap().is_some()));
+    }
+    assert_eq!(
+        written.len(),
+        streamed
+            .thumbs
+            .iter()
+            .map(|t| t.tile_refs.len())
+            .sum::<usize>()
+    );
+
+    // The marked corner is in the last tile and fades as it's averaged down.
+    let last = streamed.thumbs[0].tile_refs.last().unwrap();
+    let tile =
+        crate::codec::decode(&db.get(*last).unwrap().unwrap(), crate::TileFormat::Raw).unwrap();
+    let (w, h) = tile.dimensions();
+    assert_eq!(
+        tile.get_pixel(w - 1, h - 1),
+        ::image::Rgba([255, 0, 0, 255])
+    );
+    assert_eq!(image.dimensions(), (38, 25));
+    assert_eq!(image.get_pixel(37, 24), ::image::Rgba([8, 0, 0, 255]));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn priority_order() {
+    let db = Arc::new(Database::open(crate::store::Backend::Memory, "").unwrap());
+    let threads = Threads {
+        jobs: 1,
+        ..Threads::new(1)
+    };
+    let mut thumbnailer = Thumbnailer::new(db, threads, Options::default());
+
+    let image = |i: usize| {
+        let file = File {
+            path: format!("/nonexistent/{}.png", i),
+            ..Default::default()
+        };
+        image::Image::from(i, Arc::new(file), crate::MetadataState::Missing)
+    };
+    let priority = |hidden, mouse_dist| Priority { hidden, mouse_dist };
+
+    // The first starts straight away, the rest wait for the one job to finish.
+    thumbnailer.make_thumbs(&image(0), priority(true, 5));
+    thumbnailer.make_thumbs(&image(1), priority(true, 1));
+    thumbnailer.make_thumbs(&image(2), priority(false, 9));
+    thumbnailer.make_thumbs(&image(3), priority(false, 2));
+    thumbnailer.make_thumbs(&image(4), priority(true, 0));
+    thumbnailer.make_thumbs(&image(4), priority(true, 7));
+
+    let mut done = Vec::new();
+    while done.len() < 5 {
+        thumbnailer.wait(Duration::from_secs(10));
+        for completion in thumbnailer.recv() {
+            assert!(completion.res.is_err());
+            done.push(completion.i);
+        }
+    }
+
+    // The first either finishes before the visible images preempt it, or starts over after the
+    // nearer hidden image.
+    assert!(done == vec![0, 3, 2, 1, 4] || done == vec![3, 2, 1, 0, 4]);
+
+    thumbnailer.make_thumbs(&image(5), priority(true, 0));
+    thumbnailer.make_thumbs(&image(6), priority(true, 0));
+    thumbnailer.clear_queue();
+    thumbnailer.wait(Duration::from_secs(10));
+    assert!(thumbnailer.has_completions());
+    let done: Vec<usize> = thumbnailer.recv().into_iter().map(|c| c.i).collect();
+    assert_eq!(done, vec![5]);
+    // Nothing else was started.
+    assert!(!thumbnailer.is_busy());
+    assert!(!thumbnailer.has_completions());
+}
+
+#[test]
+fn adaptive_limit() {
+    let db = Arc::new(Database::open(crate::store::Backend::Memory, "").unwrap());
+    let threads = Threads {
+        adaptive: true,
+        ..Threads::new(2)
+    };
+    assert_eq!((threads.resize, threads.encode, threads.jobs), (1, 1, 4));
+    let mut thumbnailer = Thumbnailer::new(db, threads, Options::default());
+    let (slow, fast) = (Duration::from_millis(20), Duration::from_millis(1));
+
+    for _ in 0..10 {
+        thumbnailer.adapt(slow);
+    }
+    assert_eq!(thumbnailer.limit, 1);
+
+    for _ in 0..ADAPT_GOOD_FRAMES - 1 {
+        thumbnailer.adapt(fast);
+    }
+    assert_eq!(thumbnailer.limit, 1);
+    thumbnailer.adapt(fast);
+    assert_eq!(thumbnailer.limit, 2);
+
+    // A slow frame starts the run over.
+    thumbnailer.adapt(slow);
+    for _ in 0..ADAPT_GOOD_FRAMES {
+        thumbnailer.adapt(fast);
+    }
+    assert_eq!(thumbnailer.limit, 2);
+
+    // An update whose loader uses up the rest of the budget is within it, unless the rest of the
+    // update is slow.
+    let frame = |work: Duration| {
+        let stopwatch = crate::Stopwatch::from_millis(FRAME_BUDGET_MS);
+        std::thread::sleep(work);
+        let loader_start = stopwatch.elapsed();
+        while !stopwatch.done() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        stopwatch.elapsed_besides(loader_start, stopwatch.elapsed() - loader_start)
+    };
+    thumbnailer.adapt(frame(Duration::from_millis(2)));
+    assert_eq!((thumbnailer.limit, thumbnailer.good_frames), (2, 1));
+    thumbnailer.adapt(frame(Duration::from_millis(12)));
+    assert_eq!((thumbnailer.limit, thumbnailer.good_frames), (1, 0));
+}
+
+#[test]
+fn previews() {
+    let dir = crate::store::test_path("previews");
+    std::env::set_var("XDG_CACHE_HOME", dir.join("cache"));
+
+    let path = dir.join("blue.png");
+    ::image::RgbImage::from_pixel(600, 400, ::image::Rgb([0, 0, 255]))
+        .save(&path)
+        .unwrap();
+    let file = Arc::new(File::from_metadata(
+        path.to_str().unwrap().to_owned(),
+        &std::fs::metadata(&path).unwrap(),
+    ));
+
+    // Another program thumbnailed it already.
+    let mut shared = BTreeMap::new();
+    shared.insert(256, ::image::DynamicImage::new_rgb8(256, 171));
+    crate::xdg::publish(&file, &shared);
+
+    let db = Arc::new(Database::open(crate::store::Backend::Memory, "").unwrap());
+    let mut thumbnailer = Thumbnailer::new(Arc::clone(&db), Threads::new(1), Options::default());
+    let image = image::Image::from(0, Arc::clone(&file), crate::MetadataState::Missing);
+    thumbnailer.make_thumbs(&image, Priority::default());
+
+    let mut done: Vec<Completion> = Vec::new();
+    while done.last().is_none_or(|completion| completion.preview) {
+        thumbnailer.wait(Duration::from_secs(10));
+        done.extend(thumbnailer.recv());
+    }
+    assert_eq!(done.len(), 2);
+
+    // The shared thumbnail is shown until the original is thumbnailed, then removed.
+    assert!(done[0].preview);
+    let preview = done[0].res.as_ref().unwrap();
+    assert_eq!(preview.thumbs.last().unwrap().img_size, [256, 171]);
+    assert!(preview.tile_refs().all(|t| db.get(*t).unwrap().is_none()));
+
+    let metadata = done[1].res.as_ref().unwrap();
+    assert_eq!(metadata.thumbs.last().unwrap().img_size, [600, 400]);
+    assert_eq!(db.get_metadata(&file).unwrap().as_ref(), Some(metadata));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn broken_animation() {
+    let dir = crate::store::test_path("broken_animation");
+    let path = dir.join("broken.gif");
+
+    // The second frame is cut off.
+    let frame = |seed: u32| {
+        ::image::Frame::new(::image::RgbaImage::from_fn(16, 16, |x, y| {
+            ::image::Rgba([(x * 16) as u8, (y * seed) as u8, (x ^ y) as u8, 255])
+        }))
+    };
+    let mut gif = Vec::new();
+    ::image::gif::Encoder::new(&mut gif)
+        .encode_frames(vec![frame(3), frame(7)])
+        .unwrap();
+    gif.truncate(gif.len() - 20);
+    std::fs::write(&path, &gif).unwrap();
+
+    let path = path.to_str().unwrap();
+    assert!(crate::animation::decode(path, ANIMATION_SIZE).is_err());
+
+    let file = Arc::new(File::from_metadata(
+        path.to_owned(),
+        &std::fs::metadata(path).unwrap(),
+    ));
+    let db = Database::open(crate::store::Backend::Memory, "").unwrap();
+    let (pyramid, image) = Thumbnailer::decode(
+        file,
+        1,
+        &Options::default(),
+        &AtomicBool::new(false),
+        &db,
+        &mut Vec::new(),
+    )
+    .unwrap();
+    assert!(pyramid.frame_images.is_empty());
+    assert_eq!(image.dimensions(), (16, 16));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
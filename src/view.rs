@@ -13,6 +13,62 @@
 // limitations under the License.
 
 use crate::vec::*;
+use piston_window::{color, DrawState, G2d, Rectangle, Transformed};
+
+// Animated images play once they are drawn at least this large.
+static ANIMATION_MIN_ZOOM: f64 = 128.0;
+
+/// What the transparent parts of images are drawn over.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Background {
+    #[default]
+    Checkerboard,
+    Color([f32; 4]),
+}
+
+impl Background {
+    /// Parses "checkerboard" or an RRGGBB hex color.
+    pub fn parse(s: &str) -> Option<Self> {
+        if s == "checkerboard" {
+            return Some(Background::Checkerboard);
+        }
+        if s.len() != 6 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Background::Color(color::hex(s)))
+    }
+
+    /// Fills `rect` (in the coordinates of `trans`). Checkerboard squares are `square` wide.
+    pub fn draw(
+        &self,
+        rect: [f64; 4],
+        square: f64,
+        draw_state: &DrawState,
+        trans: [[f64; 3]; 2],
+        g: &mut G2d,
+    ) {
+        let (light, dark) = match *self {
+            Background::Color(c) => {
+                Rectangle::new(c).draw(rect, draw_state, trans, g);
+                return;
+            }
+            Background::Checkerboard => (color::hex("999999"), color::hex("666666")),
+        };
+
+        let [x, y, w, h] = rect;
+        Rectangle::new(light).draw(rect, draw_state, trans, g);
+
+        let trans = trans.trans(x, y);
+        let [cols, rows] = vec2_u32(vec2_ceil(vec2_scale([w, h], 1.0 / square)));
+        for row in 0..rows {
+            for col in (row % 2..cols).step_by(2) {
+                let [sx, sy] = vec2_scale(vec2_f64([col, row]), square);
+                let cell = [sx, sy, square.min(w - sx), square.min(h - sy)];
+                Rectangle::new(dark).draw(cell, draw_state, trans, g);
+            }
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct View {
@@ -37,6 +93,8 @@ pub struct View {
 
     // Has the user panned or zoomed?
     auto: bool,
+
+    pub alpha_background: Background,
 }
 
 impl View {
@@ -52,6 +110,10 @@ impl View {
         ret
     }
 
+    pub fn animates(&self) -> bool {
+        self.zoom >= ANIMATION_MIN_ZOOM
+    }
+
     pub fn target_size(&self) -> u32 {
         ((self.zoom * 1.5) as u32).next_power_of_two()
     }
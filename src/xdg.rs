@@ -0,0 +1,317 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// freedesktop.org shared thumbnails:
+// https://specifications.freedesktop.org/thumbnail-spec/thumbnail-spec-latest.html
+//
+// Thumbnails live in `$XDG_CACHE_HOME/thumbnails/<flavor>/<md5 of file uri>.png` and are only
+// valid while their `Thumb::MTime` (and `Thumb::Size` if present) match the original file.
+
+use crate::{File, E, R};
+use ::image::GenericImageView;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    Off,
+    /// Show shared thumbnails while images are decoded, and instead of images pix can't decode.
+    #[default]
+    Read,
+    /// Also publish generated thumbnails to the shared cache.
+    Write,
+}
+
+impl Mode {
+    pub const NAMES: &'static [&'static str] = &["off", "read", "write"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Mode::Off),
+            "read" => Some(Mode::Read),
+            "write" => Some(Mode::Write),
+            _ => None,
+        }
+    }
+
+    pub fn reads(self) -> bool {
+        self != Mode::Off
+    }
+
+    pub fn writes(self) -> bool {
+        self == Mode::Write
+    }
+}
+
+/// Thumbnail flavors, largest first.
+static FLAVORS: &[(&str, u32)] = &[
+    ("xx-large", 1024),
+    ("x-large", 512),
+    ("large", 256),
+    ("normal", 128),
+];
+
+// Everything but unreserved characters and the reserved characters allowed in a path segment,
+// matching what glib's g_filename_to_uri produces.
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')')
+    .remove(b'*')
+    .remove(b'+')
+    .remove(b',')
+    .remove(b';')
+    .remove(b'=')
+    .remove(b':')
+    .remove(b'@')
+    .remove(b'/');
+
+fn uri(path: &str) -> String {
+    format!("file://{}", utf8_percent_encode(path, PATH))
+}
+
+fn thumbnail_path(dir: &Path, flavor: &str, uri: &str) -> PathBuf {
+    dir.join(flavor)
+        .join(format!("{:x}.png", md5::compute(uri.as_bytes())))
+}
+
+fn thumbnail_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("thumbnails"))
+}
+
+/// The tEXt chunks of a png.
+fn text_chunks(png: &[u8]) -> R<BTreeMap<String, String>> {
+    let corrupt = || E::CorruptData(String::from("png"));
+
+    if png.get(..8) != Some(&[137, 80, 78, 71, 13, 10, 26, 10][..]) {
+        return Err(corrupt());
+    }
+
+    let mut ret = BTreeMap::new();
+    let mut rest = &png[8..];
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let name = &rest[4..8];
+        let data = rest.get(8..8 + len).ok_or_else(corrupt)?;
+
+        match name {
+            b"tEXt" => {
+                if let Some(nul) = data.iter().position(|&b| b == 0) {
+                    // Latin-1, but the keys and values we care about are ascii.
+                    ret.insert(
+                        String::from_utf8_lossy(&data[..nul]).into_owned(),
+                        String::from_utf8_lossy(&data[nul + 1..]).into_owned(),
+                    );
+                }
+            }
+            // Text after the image data isn't worth reading the whole file for.
+            b"IDAT" | b"IEND" => break,
+            _ => {}
+        }
+
+        // Cut off in the CRC.
+        rest = match rest.get(12 + len..) {
+            Some(rest) => rest,
+            None => break,
+        };
+    }
+
+    Ok(ret)
+}
+
+fn is_valid(text: &BTreeMap<String, String>, uri: &str, file: &File) -> bool {
+    let matches = |key: &str, value: &str| text.get(key).map(String::as_str) == Some(value);
+
+    matches("Thumb::URI", uri)
+        && matches("Thumb::MTime", &file.modified.to_string())
+        && text
+            .get("Thumb::Size")
+            .is_none_or(|size| *size == file.file_size.to_string())
+}
+
+/// Reads the largest valid shared thumbnail of `file`, returning it with its flavor size. Images
+/// smaller than the flavor aren't scaled so the result can be smaller than the size.
+pub fn lookup(file: &File) -> Option<(u32, ::image::DynamicImage)> {
+    let _s = crate::stats::ScopedDuration::new("xdg::lookup");
+
+    let dir = thumbnail_dir()?;
+    let uri = uri(&file.path);
+
+    for &(flavor, size) in FLAVORS {
+        let path = thumbnail_path(&dir, flavor, &uri);
+
+        let png = match std::fs::read(&path) {
+            Ok(png) => png,
+            Err(_) => continue,
+        };
+
+        match text_chunks(&png) {
+            Ok(ref text) if is_valid(text, &uri, file) => {}
+            _ => continue,
+        }
+
+        match ::image::load_from_memory_with_format(&png, ::image::ImageFormat::PNG) {
+            Ok(image) => return Some((size, image)),
+            Err(e) => error!("unable to decode {:?}: {:?}", path, e),
+        }
+    }
+
+    None
+}
+
+fn encode(file: &File, uri: &str, image: &::image::DynamicImage) -> R<Vec<u8>> {
+    let (w, h) = image.dimensions();
+    let rgba = image.to_rgba();
+
+    let mut buf = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut buf, w, h);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| E::IoError(e.into()))?;
+
+        let text = [
+            ("Thumb::URI", uri.to_owned()),
+            ("Thumb::MTime", file.modified.to_string()),
+            ("Thumb::Size", file.file_size.to_string()),
+            ("Software", String::from("pix")),
+        ];
+        for (key, value) in &text {
+            let mut data = Vec::with_capacity(key.len() + 1 + value.len());
+            data.extend_from_slice(key.as_bytes());
+            data.push(0);
+            data.extend_from_slice(value.as_bytes());
+            writer
+                .write_chunk(*b"tEXt", &data)
+                .map_err(|e| E::IoError(e.into()))?;
+        }
+
+        writer
+            .write_image_data(&rgba)
+            .map_err(|e| E::IoError(e.into()))?;
+    }
+
+    Ok(buf)
+}
+
+fn write(path: &Path, png: &[u8]) -> R<()> {
+    let dir = path.parent().expect("thumbnail dir");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(E::IoError)?;
+
+    // Readers must never see a partial thumbnail.
+    let tmp = dir.join(format!(
+        ".pix-{}-{:?}.png",
+        std::process::id(),
+        std::thread::current().id()
+    ));
+    let res = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(png))
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res.map_err(E::IoError)
+}
+
+/// Publishes thumbnails for every flavor `levels` has an image for, unless a valid one exists.
+/// `levels` maps pyramid bucket sizes to images that fit in them.
+pub fn publish(file: &File, levels: &BTreeMap<u32, ::image::DynamicImage>) {
+    let _s = crate::stats::ScopedDuration::new("xdg::publish");
+
+    let dir = match thumbnail_dir() {
+        Some(dir) => dir,
+        None => return,
+    };
+    let uri = uri(&file.path);
+
+    for &(flavor, size) in FLAVORS {
+        let image = match levels.get(&size) {
+            Some(image) => image,
+            None => continue,
+        };
+
+        let path = thumbnail_path(&dir, flavor, &uri);
+
+        let valid = std::fs::read(&path)
+            .ok()
+            .and_then(|png| text_chunks(&png).ok())
+            .is_some_and(|text| is_valid(&text, &uri, file));
+        if valid {
+            continue;
+        }
+
+        if let Err(e) = encode(file, &uri, image).and_then(|png| write(&path, &png)) {
+            error!("unable to publish {:?}: {:?}", path, e);
+        }
+    }
+}
+
+#[test]
+fn file_uri() {
+    assert_eq!(uri("/a/b c/ü#1.png"), "file:///a/b%20c/%C3%BC%231.png");
+    assert_eq!(
+        thumbnail_path(Path::new("/t"), "normal", "file:///home/jens/photos/me.png"),
+        Path::new("/t/normal/c6ee772d9e49320e97ec29a7eb5b1697.png")
+    );
+}
+
+#[test]
+fn round_trip() {
+    let file = File {
+        path: String::from("/round trip.png"),
+        modified: 1234,
+        file_size: 5678,
+    };
+    let uri = uri(&file.path);
+    let image = ::image::DynamicImage::new_rgba8(3, 2);
+
+    let png = encode(&file, &uri, &image).unwrap();
+    let text = text_chunks(&png).unwrap();
+    assert!(is_valid(&text, &uri, &file));
+    assert!(!is_valid(
+        &text,
+        &uri,
+        &File {
+            modified: 1235,
+            ..file.clone()
+        }
+    ));
+
+    let decoded = ::image::load_from_memory(&png).unwrap();
+    assert_eq!(decoded.dimensions(), (3, 2));
+
+    // Other programs write these, they may be cut off anywhere.
+    for len in 0..png.len() {
+        let _ = text_chunks(&png[..len]);
+    }
+}